@@ -0,0 +1,182 @@
+// On-disk cache of the synthesized audio, so repeated phrases are synthesized only once
+#![allow(dead_code)]
+
+use std::{ collections::HashMap, path::PathBuf, time::{ Duration, SystemTime, UNIX_EPOCH } };
+
+use anyhow::Result;
+use serde::{ Deserialize, Serialize };
+use sha2::{ Digest, Sha256 };
+
+use crate::colors::Colorize;
+use crate::tts_engine::SpeechAudio;
+
+const INDEX_FILE: &str = "index.json";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AudioCacheConfig {
+    pub enabled: bool,
+    /// Directory holding the cached clips and their index
+    pub path: String,
+    /// Least recently used clips are deleted above this size
+    pub max_size_mb: u64,
+}
+
+impl Default for AudioCacheConfig {
+    fn default() -> Self {
+        AudioCacheConfig {
+            enabled: true,
+            path: "tts_cache".into(),
+            max_size_mb: 100,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct CacheEntry {
+    format: String,
+    duration_ms: u64,
+    size: u64,
+    /// Unix time in milliseconds of the last use
+    last_used: u64,
+}
+
+/// Content addressed: the key is the hash of everything that changes the audio
+#[derive(Debug)]
+pub struct AudioCache {
+    config: AudioCacheConfig,
+    entries: HashMap<String, CacheEntry>,
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+impl AudioCache {
+    /// Loads the cache index, starting with an empty cache if it does not exist yet
+    pub fn load(config: AudioCacheConfig) -> Result<Self> {
+        let mut cache = AudioCache { config, entries: HashMap::new() };
+        if !cache.config.enabled {
+            return Ok(cache);
+        }
+        std::fs::create_dir_all(&cache.config.path)?;
+        if let Ok(content) = std::fs::read_to_string(cache.index_path()) {
+            cache.entries = serde_json::from_str(&content)?;
+        }
+        // Clips deleted by hand are forgotten
+        cache.entries.retain(|key, _| PathBuf::from(&cache.config.path).join(key).exists());
+        println!(
+            "[TTS][CACHE] Loaded {} clips, {} KB",
+            cache.entries.len(),
+            cache.size() / 1024
+        );
+        Ok(cache)
+    }
+
+    /// Cache keeping nothing, when the cache directory is not usable
+    pub fn disabled() -> Self {
+        AudioCache {
+            config: AudioCacheConfig { enabled: false, ..AudioCacheConfig::default() },
+            entries: HashMap::new(),
+        }
+    }
+
+    fn index_path(&self) -> PathBuf {
+        PathBuf::from(&self.config.path).join(INDEX_FILE)
+    }
+
+    fn clip_path(&self, key: &str) -> PathBuf {
+        PathBuf::from(&self.config.path).join(key)
+    }
+
+    async fn save(&self) -> Result<()> {
+        tokio::fs::write(self.index_path(), serde_json::to_string(&self.entries)?).await?;
+        Ok(())
+    }
+
+    /// Total size of the cached clips in bytes
+    pub fn size(&self) -> u64 {
+        self.entries
+            .values()
+            .map(|entry| entry.size)
+            .sum()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Key of the clip reading `ssml` with `voice`, prosody and format
+    pub fn key(ssml: &str, voice: &str, rate: i32, pitch: i32, volume: i32, format: &str) -> String {
+        let mut hasher = Sha256::new();
+        for part in [ssml, voice, &rate.to_string(), &pitch.to_string(), &volume.to_string(), format] {
+            hasher.update(part.as_bytes());
+            // Separator, so ("ab", "c") and ("a", "bc") differ
+            hasher.update([0u8]);
+        }
+        hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// Clip cached under `key`. Its last use is only kept in memory, the index is written by the next `put`
+    pub async fn get(&mut self, key: &str) -> Option<SpeechAudio> {
+        if !self.config.enabled {
+            return None;
+        }
+        let entry = self.entries.get_mut(key)?;
+        entry.last_used = now_millis();
+        let (format, duration) = (entry.format.clone(), Duration::from_millis(entry.duration_ms));
+
+        match tokio::fs::read(self.clip_path(key)).await {
+            Ok(bytes) => Some(SpeechAudio { format, bytes, duration }),
+            Err(_) => {
+                self.entries.remove(key);
+                None
+            }
+        }
+    }
+
+    pub async fn put(&mut self, key: &str, audio: &SpeechAudio) {
+        if !self.config.enabled {
+            return;
+        }
+        if let Err(err) = tokio::fs::write(self.clip_path(key), &audio.bytes).await {
+            println!("{} {}", "[TTS][CACHE]".red(), err);
+            return;
+        }
+        self.entries.insert(key.to_string(), CacheEntry {
+            format: audio.format.clone(),
+            duration_ms: audio.duration.as_millis() as u64,
+            size: audio.bytes.len() as u64,
+            last_used: now_millis(),
+        });
+        self.evict().await;
+        if let Err(err) = self.save().await {
+            println!("{} {}", "[TTS][CACHE]".red(), err);
+        }
+    }
+
+    /// Deletes the least recently used clips until the cache fits its size limit
+    async fn evict(&mut self) {
+        let max_size = self.config.max_size_mb * 1024 * 1024;
+        let mut size = self.size();
+        while size > max_size {
+            let Some(oldest) = self.entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone()) else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&oldest) {
+                size -= entry.size;
+            }
+            _ = tokio::fs::remove_file(self.clip_path(&oldest)).await;
+        }
+    }
+}
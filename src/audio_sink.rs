@@ -0,0 +1,218 @@
+// Destinations of the synthesized TTS audio
+#![allow(dead_code)]
+
+use std::{ process::Stdio, sync::Arc, time::Duration };
+
+use anyhow::{ anyhow, Result };
+use serde::{ Deserialize, Serialize };
+use tokio::{ io::AsyncWriteExt, net::TcpListener, process::Command, sync::broadcast };
+
+use crate::colors::Colorize;
+use crate::overlay::OverlaySink;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AudioSinkConfig {
+    /// Write every clip to a numbered file in `path`
+    Directory {
+        path: String,
+    },
+    /// Run `program` for every clip, writing the audio to its stdin, e.g. mpv -
+    Command {
+        program: String,
+        args: Vec<String>,
+    },
+    /// Stream the raw audio to every TCP client connected to `address`
+    Stream {
+        address: String,
+    },
+    /// Serve a browser source overlay on `address` playing the clips with captions
+    Overlay {
+        address: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioClip {
+    pub sequence: u64,
+    /// msedge-tts audio format, e.g. audio-24khz-48kbitrate-mono-mp3
+    pub format: String,
+    pub bytes: Vec<u8>,
+    pub text: String,
+    pub voice: String,
+    /// Display name of the chatter the text comes from
+    pub speaker: String,
+    /// Chat color of the speaker, e.g. #FF4500, empty if unknown
+    pub color: String,
+    pub duration: Duration,
+}
+
+impl AudioClip {
+    pub fn file_extension(&self) -> &'static str {
+        match self.format.as_str() {
+            format if format.contains("mp3") => "mp3",
+            format if format.starts_with("ogg") => "ogg",
+            format if format.starts_with("webm") => "webm",
+            format if format.starts_with("riff") => "wav",
+            format if format.contains("opus") => "opus",
+            _ => "raw",
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self.file_extension() {
+            "mp3" => "audio/mpeg",
+            "ogg" | "opus" => "audio/ogg",
+            "webm" => "audio/webm",
+            "wav" => "audio/wav",
+            _ => "application/octet-stream",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct DirectorySink {
+    path: String,
+    /// Files already in the directory, so numbering goes on after a restart
+    first_sequence: u64,
+}
+
+impl DirectorySink {
+    pub fn new(path: &str) -> Result<Self> {
+        std::fs::create_dir_all(path)?;
+        let first_sequence = std::fs::read_dir(path)?.count() as u64;
+        Ok(DirectorySink { path: path.to_string(), first_sequence })
+    }
+
+    async fn play(&self, clip: &AudioClip) -> Result<()> {
+        let file_name = format!(
+            "{}/{:06}_{}.{}",
+            self.path,
+            self.first_sequence + clip.sequence,
+            clip.voice.replace(['/', '\\', ' ', ':'], "_"),
+            clip.file_extension()
+        );
+        tokio::fs::write(&file_name, &clip.bytes).await?;
+        println!("{} Saved {}", "[TTS][SINK]".cyan(), file_name);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct CommandSink {
+    program: String,
+    args: Vec<String>,
+}
+
+impl CommandSink {
+    async fn play(&self, clip: &AudioClip) -> Result<()> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            // A skipped clip drops the future, stopping the player too
+            .kill_on_drop(true)
+            .spawn()?;
+        let mut stdin = child.stdin.take().ok_or_else(|| anyhow!("no stdin for {}", self.program))?;
+        stdin.write_all(&clip.bytes).await?;
+        // Closing stdin tells the player the clip is over
+        drop(stdin);
+
+        let status = child.wait().await?;
+        if !status.success() {
+            return Err(anyhow!("{} exited with {}", self.program, status));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct StreamSink {
+    clips: broadcast::Sender<Arc<AudioClip>>,
+}
+
+impl StreamSink {
+    /// Starts listening for clients on `address`
+    pub async fn bind(address: &str) -> Result<Self> {
+        let listener = TcpListener::bind(address).await?;
+        println!("{} Streaming audio on {}", "[TTS][SINK]".cyan(), address);
+        let (clips, _) = broadcast::channel::<Arc<AudioClip>>(16);
+
+        let sender = clips.clone();
+        tokio::spawn(async move {
+            loop {
+                let Ok((mut socket, peer)) = listener.accept().await else {
+                    continue;
+                };
+                println!("{} Client {} connected", "[TTS][SINK]".cyan(), peer);
+                let mut receiver = sender.subscribe();
+                tokio::spawn(async move {
+                    loop {
+                        let clip = match receiver.recv().await {
+                            Ok(clip) => clip,
+                            // Slow client, the missed clips are lost
+                            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                            Err(broadcast::error::RecvError::Closed) => break,
+                        };
+                        if socket.write_all(&clip.bytes).await.is_err() {
+                            break;
+                        }
+                    }
+                    println!("{} Client {} disconnected", "[TTS][SINK]".cyan(), peer);
+                });
+            }
+        });
+
+        Ok(StreamSink { clips })
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<AudioClip>> {
+        self.clips.subscribe()
+    }
+
+    async fn play(&self, clip: &AudioClip) -> Result<()> {
+        // No connected client is not an error, the clip is simply not heard
+        _ = self.clips.send(Arc::new(clip.clone()));
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum AudioSink {
+    Directory(DirectorySink),
+    Command(CommandSink),
+    Stream(StreamSink),
+    Overlay(OverlaySink),
+}
+
+impl AudioSink {
+    pub async fn from_config(config: &AudioSinkConfig) -> Result<Self> {
+        Ok(match config {
+            AudioSinkConfig::Directory { path } => AudioSink::Directory(DirectorySink::new(path)?),
+            AudioSinkConfig::Command { program, args } =>
+                AudioSink::Command(CommandSink {
+                    program: program.clone(),
+                    args: args.clone(),
+                }),
+            AudioSinkConfig::Stream { address } => AudioSink::Stream(StreamSink::bind(address).await?),
+            AudioSinkConfig::Overlay { address } =>
+                AudioSink::Overlay(OverlaySink::bind(address).await?),
+        })
+    }
+
+    pub async fn play(&self, clip: &AudioClip) -> Result<()> {
+        match self {
+            AudioSink::Directory(sink) => sink.play(clip).await,
+            AudioSink::Command(sink) => sink.play(clip).await,
+            AudioSink::Stream(sink) => sink.play(clip).await,
+            AudioSink::Overlay(sink) => sink.play(clip).await,
+        }
+    }
+
+    /// Stops the clip being played, where the sink can
+    pub async fn stop(&self) {
+        if let AudioSink::Overlay(sink) = self {
+            sink.stop();
+        }
+    }
+}
@@ -0,0 +1,97 @@
+// Chat message with its origin and metadata, passed along the event bus instead of the bare text
+#![allow(dead_code)]
+
+use std::sync::atomic::{ AtomicU64, Ordering };
+
+use chrono::{ DateTime, Local, TimeDelta };
+
+use crate::chat_state::Permission;
+use crate::irc_parser::IrcMessage;
+
+static NEXT_CORRELATION_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Author {
+    /// Lowercase login name
+    pub login: String,
+    pub display_name: String,
+    pub user_id: String,
+    /// Chat color, e.g. #FF4500, empty if never set
+    pub color: String,
+    pub permission: Permission,
+    /// Badge names without their version, e.g. broadcaster, subscriber
+    pub badges: Vec<String>,
+}
+
+impl Author {
+    pub fn from_message(message: &IrcMessage) -> Self {
+        Author {
+            login: message.context.sender.clone(),
+            display_name: message.tag("display-name").unwrap_or(&message.context.sender).to_string(),
+            user_id: message.tag("user-id").unwrap_or_default().to_string(),
+            color: message.tag("color").unwrap_or_default().to_string(),
+            permission: Permission::from_message(message),
+            badges: message
+                .tag("badges")
+                .unwrap_or_default()
+                .split(',')
+                .filter_map(|badge| badge.split('/').next())
+                .filter(|badge| !badge.is_empty())
+                .map(|badge| badge.to_string())
+                .collect(),
+        }
+    }
+
+    pub fn has_badge(&self, badge: &str) -> bool {
+        self.badges.iter().any(|author_badge| author_badge == badge)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChatEnvelope {
+    /// Follows the message through every module, e.g. to match a reply with its question
+    pub correlation_id: u64,
+    /// Channel the message comes from, without the #
+    pub channel: String,
+    pub author: Author,
+    pub message: IrcMessage,
+    pub created_at: DateTime<Local>,
+    /// Envelopes with a higher priority are served first, e.g. cheers and redemptions
+    pub priority: u32,
+}
+
+impl ChatEnvelope {
+    pub fn new(message: IrcMessage) -> Self {
+        ChatEnvelope {
+            correlation_id: NEXT_CORRELATION_ID.fetch_add(1, Ordering::Relaxed),
+            channel: message.context.destination.trim_start_matches('#').to_string(),
+            author: Author::from_message(&message),
+            message,
+            created_at: Local::now(),
+            priority: 0,
+        }
+    }
+
+    pub fn with_priority(mut self, priority: u32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn text(&self) -> &str {
+        &self.message.payload
+    }
+
+    /// Twitch id of the message, needed to reply to it or delete it
+    pub fn message_id(&self) -> Option<&str> {
+        self.message.tag("id")
+    }
+
+    pub fn age(&self) -> TimeDelta {
+        Local::now() - self.created_at
+    }
+
+    /// Short reference for the logs, e.g. "icsboyx#42"
+    pub fn trace_id(&self) -> String {
+        format!("{}#{}", self.channel, self.correlation_id)
+    }
+}
@@ -0,0 +1,123 @@
+// Conversation history of a channel, with the rolling summary of the oldest turns
+#![allow(dead_code)]
+
+use ollama_rs::generation::chat::ChatMessage;
+
+/// Rough token count, good enough to know when the context window is getting full
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ChatHistory {
+    summary: Option<String>,
+    messages: Vec<ChatMessage>,
+    /// The oldest turns are out being summarized
+    summarizing: bool,
+}
+
+impl ChatHistory {
+    pub fn new() -> Self {
+        ChatHistory {
+            summary: None,
+            messages: Vec::new(),
+            summarizing: false,
+        }
+    }
+
+    pub fn push(&mut self, message: ChatMessage) {
+        self.messages.push(message);
+    }
+
+    /// Full request: system prompts, summary and the recent turns
+    pub fn messages(&self, system: &[String]) -> Vec<ChatMessage> {
+        let mut messages = system
+            .iter()
+            .map(|system| ChatMessage::system(system.clone()))
+            .collect::<Vec<ChatMessage>>();
+        if let Some(summary) = &self.summary {
+            messages.push(
+                ChatMessage::system(format!("Summary of the conversation so far:\n{}", summary))
+            );
+        }
+        messages.extend(self.messages.iter().cloned());
+        messages
+    }
+
+    /// Tokens of the full request with the `system` prompts
+    pub fn estimated_tokens(&self, system: &[String]) -> usize {
+        self.messages(system)
+            .iter()
+            .map(|message| estimate_tokens(&message.content))
+            .sum()
+    }
+
+    /// Removes and returns the oldest turns, keeping the `keep_recent` most recent ones.
+    /// Nothing while a previous batch is being summarized.
+    pub fn take_oldest(&mut self, keep_recent: usize) -> Vec<ChatMessage> {
+        if self.summarizing {
+            return Vec::new();
+        }
+        let split = self.messages.len().saturating_sub(keep_recent);
+        let oldest = self.messages.drain(..split).collect::<Vec<ChatMessage>>();
+        self.summarizing = !oldest.is_empty();
+        oldest
+    }
+
+    /// Puts back the turns taken by `take_oldest` when they could not be summarized
+    pub fn restore_oldest(&mut self, oldest: Vec<ChatMessage>) {
+        self.messages.splice(..0, oldest);
+        self.summarizing = false;
+    }
+
+    pub fn is_summarizing(&self) -> bool {
+        self.summarizing
+    }
+
+    pub fn summary(&self) -> Option<&str> {
+        self.summary.as_deref()
+    }
+
+    pub fn set_summary(&mut self, summary: String) {
+        self.summary = Some(summary);
+        self.summarizing = false;
+    }
+
+    pub fn reset_summary(&mut self) {
+        self.summary = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history(turns: usize) -> ChatHistory {
+        let mut history = ChatHistory::new();
+        for turn in 0..turns {
+            history.push(ChatMessage::user(format!("turn {}", turn)));
+        }
+        history
+    }
+
+    #[test]
+    fn estimate_counts_the_system_prompts() {
+        let history = history(2);
+        let system = vec!["x".repeat(400)];
+        assert_eq!(history.estimated_tokens(&system), history.estimated_tokens(&[]) + 100);
+    }
+
+    #[test]
+    fn one_batch_is_summarized_at_a_time() {
+        let mut history = history(5);
+        let oldest = history.take_oldest(2);
+        assert_eq!(oldest.len(), 3);
+        assert!(history.take_oldest(0).is_empty());
+
+        history.restore_oldest(oldest);
+        assert_eq!(history.messages(&[])[0].content, "turn 0");
+        assert_eq!(history.take_oldest(4).len(), 1);
+        history.set_summary("summary".into());
+        assert!(!history.is_summarizing());
+    }
+}
@@ -0,0 +1,130 @@
+// Shared view of the channel, updated by the twitch client and read by the other modules
+#![allow(dead_code)]
+
+use std::{ collections::{ HashMap, HashSet, VecDeque }, sync::Arc };
+
+use chrono::{ DateTime, Local };
+use serde::{ Deserialize, Serialize };
+use tokio::sync::RwLock;
+
+use crate::irc_parser::IrcMessage;
+
+/// Number of chat lines kept for the prompts
+const RECENT_CHAT_LINES: usize = 20;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    #[default]
+    Everyone,
+    Subscriber,
+    Vip,
+    Moderator,
+    Broadcaster,
+}
+
+impl Permission {
+    /// Highest permission granted by the twitch `badges` tag, e.g. "moderator/1,subscriber/12"
+    pub fn from_badges(badges: &str) -> Self {
+        badges
+            .split(',')
+            .filter_map(|badge| badge.split('/').next())
+            .map(|badge| {
+                match badge {
+                    "broadcaster" => Permission::Broadcaster,
+                    "moderator" => Permission::Moderator,
+                    "vip" => Permission::Vip,
+                    "subscriber" | "founder" => Permission::Subscriber,
+                    _ => Permission::Everyone,
+                }
+            })
+            .max()
+            .unwrap_or_default()
+    }
+
+    pub fn from_message(message: &IrcMessage) -> Self {
+        let permission = Permission::from_badges(message.tag("badges").unwrap_or_default());
+        if message.tag("mod") == Some("1") {
+            return permission.max(Permission::Moderator);
+        }
+        permission
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatState {
+    started_at: DateTime<Local>,
+    room_state: Arc<RwLock<HashMap<String, String>>>,
+    user_permissions: Arc<RwLock<HashMap<String, Permission>>>,
+    bot_permission: Arc<RwLock<Permission>>,
+    recent_chat: Arc<RwLock<VecDeque<String>>>,
+}
+
+impl Default for ChatState {
+    fn default() -> Self {
+        ChatState {
+            started_at: Local::now(),
+            room_state: Arc::default(),
+            user_permissions: Arc::default(),
+            bot_permission: Arc::default(),
+            recent_chat: Arc::default(),
+        }
+    }
+}
+
+impl ChatState {
+    pub fn started_at(&self) -> DateTime<Local> {
+        self.started_at
+    }
+
+    /// Merges the tags of a ROOMSTATE message, twitch only sends the changed ones
+    pub async fn update_room_state(&self, message: &IrcMessage) {
+        let mut room_state = self.room_state.write().await;
+        for (key, value) in &message.token {
+            if !key.is_empty() {
+                room_state.insert(key.clone(), value.clone());
+            }
+        }
+    }
+
+    pub async fn get_room_state(&self) -> HashMap<String, String> {
+        self.room_state.read().await.clone()
+    }
+
+    pub async fn set_user_permission(&self, user: &str, permission: Permission) {
+        self.user_permissions.write().await.insert(user.to_lowercase(), permission);
+    }
+
+    pub async fn get_user_permission(&self, user: &str) -> Permission {
+        self.user_permissions
+            .read().await
+            .get(&user.to_lowercase())
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Lowercase nicknames of the users seen in the chat
+    pub async fn get_participants(&self) -> HashSet<String> {
+        self.user_permissions.read().await.keys().cloned().collect()
+    }
+
+    pub async fn set_bot_permission(&self, permission: Permission) {
+        *self.bot_permission.write().await = permission;
+    }
+
+    pub async fn get_bot_permission(&self) -> Permission {
+        *self.bot_permission.read().await
+    }
+
+    pub async fn push_recent_chat(&self, line: String) {
+        let mut recent_chat = self.recent_chat.write().await;
+        recent_chat.push_back(line);
+        while recent_chat.len() > RECENT_CHAT_LINES {
+            recent_chat.pop_front();
+        }
+    }
+
+    pub async fn get_recent_chat(&self) -> Vec<String> {
+        self.recent_chat.read().await.iter().cloned().collect()
+    }
+}
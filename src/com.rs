@@ -1,45 +1,346 @@
 #![allow(dead_code)]
-use std::{ collections::VecDeque, sync::Arc };
+use std::{ collections::VecDeque, fmt, sync::Mutex };
 
-use tokio::sync::{ Notify, RwLock };
+use tokio::sync::Notify;
+
+/// What a full queue does with a new message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueuePolicy {
+    /// `send` waits until a consumer makes room
+    Block,
+    /// The message waiting the longest is dropped to make room
+    DropOldest,
+    /// The new message is dropped
+    DropNewest,
+    /// The new message is refused, `try_send` gives it back
+    Error,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum QueueError<T> {
+    /// The queue is at capacity, the message is given back
+    Full(T),
+}
+
+impl<T> fmt::Display for QueueError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueueError::Full(_) => write!(f, "queue full"),
+        }
+    }
+}
+
+impl<T: fmt::Debug> std::error::Error for QueueError<T> {}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueMetrics {
+    pub len: usize,
+    /// None for an unbounded queue
+    pub capacity: Option<usize>,
+    /// Most messages ever waiting at the same time
+    pub high_watermark: usize,
+    /// Messages lost because the queue was full
+    pub dropped: u64,
+}
+
+#[derive(Debug)]
+struct QueueState<T> {
+    messages: VecDeque<T>,
+    high_watermark: usize,
+    dropped: u64,
+}
 
 #[derive(Debug)]
 pub struct MessageQueue<T> {
-    msg: Arc<RwLock<VecDeque<T>>>,
+    // Never held across an await
+    state: Mutex<QueueState<T>>,
+    capacity: Option<usize>,
+    policy: QueuePolicy,
+    /// Wakes the consumers waiting for a message
     notifier: Notify,
+    /// Wakes the senders waiting for room
+    space: Notify,
 }
 
 impl<T> MessageQueue<T> {
     pub fn new() -> Self {
         MessageQueue {
-            msg: Arc::new(RwLock::new(VecDeque::new())),
+            state: Mutex::new(QueueState { messages: VecDeque::new(), high_watermark: 0, dropped: 0 }),
+            capacity: None,
+            policy: QueuePolicy::Block,
             notifier: Notify::new(),
+            space: Notify::new(),
         }
     }
 
-    pub async fn send(&self, message: T) {
-        self.msg.write().await.push_back(message);
-        self.notifier.notify_waiters();
+    /// Queue holding at most `capacity` messages, `policy` decides what happens past it
+    pub fn bounded(capacity: usize, policy: QueuePolicy) -> Self {
+        MessageQueue {
+            capacity: Some(capacity.max(1)),
+            policy,
+            ..MessageQueue::new()
+        }
     }
 
+    fn state(&self) -> std::sync::MutexGuard<'_, QueueState<T>> {
+        // A panic while holding the lock cannot leave the deque inconsistent
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Queues `message` without waiting. A full queue applies its policy:
+    /// DropOldest makes room, the others give the message back.
+    pub fn try_send(&self, message: T) -> Result<(), QueueError<T>> {
+        self.try_send_evicting(message).map(|_| ())
+    }
+
+    /// Same as `try_send`, also returning the message DropOldest dropped to make room
+    pub fn try_send_evicting(&self, message: T) -> Result<Option<T>, QueueError<T>> {
+        let mut evicted = None;
+        {
+            let mut state = self.state();
+            if self.capacity.is_some_and(|capacity| state.messages.len() >= capacity) {
+                match self.policy {
+                    QueuePolicy::DropOldest => {
+                        evicted = state.messages.pop_front();
+                        state.dropped += 1;
+                    }
+                    QueuePolicy::DropNewest => {
+                        state.dropped += 1;
+                        return Err(QueueError::Full(message));
+                    }
+                    QueuePolicy::Block | QueuePolicy::Error => {
+                        return Err(QueueError::Full(message));
+                    }
+                }
+            }
+            state.messages.push_back(message);
+            state.high_watermark = state.high_watermark.max(state.messages.len());
+        }
+        // One wakeup per message, kept as a permit if no consumer is waiting yet
+        self.notifier.notify_one();
+        Ok(evicted)
+    }
+
+    /// Queues `message`, waiting for room with the Block policy.
+    /// A full queue with the Error policy gives the message back, DropNewest drops it and returns Ok.
+    pub async fn send(&self, message: T) -> Result<(), QueueError<T>> {
+        let mut message = message;
+        loop {
+            // Registered before trying, so room made in between is not missed
+            let space = self.space.notified();
+            tokio::pin!(space);
+            space.as_mut().enable();
+
+            match self.try_send(message) {
+                Ok(()) => {
+                    return Ok(());
+                }
+                Err(QueueError::Full(returned)) if self.policy == QueuePolicy::Block => {
+                    message = returned;
+                    space.await;
+                }
+                Err(err) if self.policy == QueuePolicy::Error => {
+                    return Err(err);
+                }
+                // DropNewest, already counted
+                Err(QueueError::Full(_)) => {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Oldest message, None if the queue is empty
+    pub fn try_recv(&self) -> Option<T> {
+        let message = self.state().messages.pop_front()?;
+        self.space.notify_one();
+        Some(message)
+    }
+
+    /// Waits for the oldest message. With several consumers every message goes to exactly one
+    /// of them, and waiting consumers are served in the order they started waiting.
     pub async fn recv(&self) -> T {
         loop {
-            if !self.is_empty().await {
-                return self.msg.write().await.pop_front().unwrap();
+            // Registered before checking, so a message sent in between wakes this consumer
+            let notified = self.notifier.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            if let Some(message) = self.try_recv() {
+                // The wakeup may have been meant for this message, pass it on if others are waiting
+                if !self.is_empty() {
+                    self.notifier.notify_one();
+                }
+                return message;
             }
-            self.notifier.notified().await;
+            notified.await;
         }
     }
 
-    async fn is_empty(&self) -> bool {
-        self.msg.read().await.is_empty()
+    pub fn is_empty(&self) -> bool {
+        self.state().messages.is_empty()
     }
 
-    async fn len(&self) -> usize {
-        self.msg.read().await.len()
+    pub fn len(&self) -> usize {
+        self.state().messages.len()
     }
 
-    async fn clear(&self) {
-        self.msg.write().await.clear();
+    pub fn capacity(&self) -> Option<usize> {
+        self.capacity
+    }
+
+    /// Drops every waiting message, returns how many there were
+    pub fn clear(&self) -> usize {
+        let cleared = {
+            let mut state = self.state();
+            let cleared = state.messages.len();
+            state.messages.clear();
+            cleared
+        };
+        self.space.notify_waiters();
+        cleared
+    }
+
+    pub fn metrics(&self) -> QueueMetrics {
+        let state = self.state();
+        QueueMetrics {
+            len: state.messages.len(),
+            capacity: self.capacity,
+            high_watermark: state.high_watermark,
+            dropped: state.dropped,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ collections::HashSet, sync::Arc, time::Duration };
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn every_message_is_delivered_exactly_once() {
+        const PRODUCERS: usize = 4;
+        const CONSUMERS: usize = 4;
+        const MESSAGES: usize = 2000;
+
+        for queue in [MessageQueue::new(), MessageQueue::bounded(8, QueuePolicy::Block)] {
+            let queue = Arc::new(queue);
+            let consumers = (0..CONSUMERS)
+                .map(|_| {
+                    let queue = queue.clone();
+                    tokio::spawn(async move {
+                        let mut received = Vec::new();
+                        while let Some(message) = queue.recv().await {
+                            received.push(message);
+                        }
+                        received
+                    })
+                })
+                .collect::<Vec<_>>();
+            let producers = (0..PRODUCERS)
+                .map(|producer| {
+                    let queue = queue.clone();
+                    tokio::spawn(async move {
+                        for message in 0..MESSAGES {
+                            queue.send(Some(producer * MESSAGES + message)).await.unwrap();
+                            if message % 97 == 0 {
+                                tokio::task::yield_now().await;
+                            }
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            let received = tokio::time::timeout(TIMEOUT, async {
+                for producer in producers {
+                    producer.await.unwrap();
+                }
+                for _ in 0..CONSUMERS {
+                    queue.send(None).await.unwrap();
+                }
+                let mut received = Vec::new();
+                for consumer in consumers {
+                    received.extend(consumer.await.unwrap());
+                }
+                received
+            }).await.expect("a consumer missed a wakeup");
+
+            assert_eq!(received.len(), PRODUCERS * MESSAGES);
+            assert_eq!(received.into_iter().collect::<HashSet<_>>().len(), PRODUCERS * MESSAGES);
+        }
+    }
+
+    #[tokio::test]
+    async fn message_sent_after_enable_wakes_the_consumer() {
+        let queue = MessageQueue::new();
+        // The steps of recv, with the message arriving between the check and the wait
+        let notified = queue.notifier.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        assert_eq!(queue.try_recv(), None);
+
+        queue.try_send(1).unwrap();
+        tokio::time::timeout(TIMEOUT, notified).await.expect("wakeup lost");
+        assert_eq!(queue.try_recv(), Some(1));
+    }
+
+    #[tokio::test]
+    async fn message_sent_before_recv_is_received() {
+        let queue = MessageQueue::new();
+        queue.try_send(1).unwrap();
+        assert_eq!(tokio::time::timeout(TIMEOUT, queue.recv()).await, Ok(1));
+    }
+
+    async fn blocked_sender(queue: &Arc<MessageQueue<i32>>) -> tokio::task::JoinHandle<Result<(), QueueError<i32>>> {
+        queue.try_send(1).unwrap();
+        let sender = {
+            let queue = queue.clone();
+            tokio::spawn(async move { queue.send(2).await })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!sender.is_finished());
+        sender
+    }
+
+    #[tokio::test]
+    async fn blocked_sender_is_woken_by_try_recv() {
+        let queue = Arc::new(MessageQueue::bounded(1, QueuePolicy::Block));
+        let sender = blocked_sender(&queue).await;
+
+        assert_eq!(queue.try_recv(), Some(1));
+        tokio::time::timeout(TIMEOUT, sender).await.expect("sender not woken").unwrap().unwrap();
+        assert_eq!(queue.try_recv(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn blocked_sender_is_woken_by_clear() {
+        let queue = Arc::new(MessageQueue::bounded(1, QueuePolicy::Block));
+        let sender = blocked_sender(&queue).await;
+
+        assert_eq!(queue.clear(), 1);
+        tokio::time::timeout(TIMEOUT, sender).await.expect("sender not woken").unwrap().unwrap();
+        assert_eq!(queue.try_recv(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn overflow_policies() {
+        let queue = MessageQueue::bounded(1, QueuePolicy::Error);
+        queue.send(1).await.unwrap();
+        assert_eq!(queue.send(2).await, Err(QueueError::Full(2)));
+
+        let queue = MessageQueue::bounded(1, QueuePolicy::DropNewest);
+        queue.send(1).await.unwrap();
+        queue.send(2).await.unwrap();
+        assert_eq!(queue.try_recv(), Some(1));
+
+        let queue = MessageQueue::bounded(1, QueuePolicy::DropOldest);
+        queue.send(1).await.unwrap();
+        queue.send(2).await.unwrap();
+        assert_eq!(queue.try_recv(), Some(2));
+        assert_eq!(queue.metrics().dropped, 1);
+        assert_eq!(queue.metrics().high_watermark, 1);
     }
 }
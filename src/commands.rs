@@ -0,0 +1,115 @@
+// Chat commands ("!name arguments") handled by the bot itself
+#![allow(dead_code)]
+
+use std::sync::Arc;
+
+use crate::chat_envelope::ChatEnvelope;
+use crate::chat_state::Permission;
+use crate::event_bus::BotEvent;
+use crate::ollama::{ LlmRequest, LlmRequestKind };
+use crate::tts::TtsControl;
+use crate::Args;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatCommand {
+    pub name: String,
+    pub arguments: Vec<String>,
+}
+
+impl ChatCommand {
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut words = text.trim().strip_prefix('!')?.split_whitespace();
+        let name = words.next()?.to_lowercase();
+        Some(ChatCommand {
+            name,
+            arguments: words.map(|word| word.to_string()).collect(),
+        })
+    }
+
+    pub fn argument(&self, index: usize) -> Option<&str> {
+        self.arguments.get(index).map(|argument| argument.as_str())
+    }
+}
+
+/// Runs the command if it is a bot command, returns false if the message must be handled as chat
+pub async fn dispatch(args: &Arc<Args>, envelope: &ChatEnvelope, command: &ChatCommand) -> bool {
+    let sender = &envelope.author.login;
+    let permission = envelope.author.permission;
+
+    match command.name.as_str() {
+        "summary" => {
+            if permission < Permission::Moderator {
+                println!("[COMMAND] {} is not allowed to use !{}", sender, command.name);
+                return true;
+            }
+            let kind = match command.argument(0) {
+                Some("reset") => LlmRequestKind::ResetSummary,
+                _ => LlmRequestKind::ShowSummary,
+            };
+            args.events.publish(BotEvent::LlmRequest(LlmRequest::new(sender, "", kind).with_envelope(envelope.clone())));
+            true
+        }
+        "persona" => {
+            let channel = &envelope.channel;
+            let Some(name) = command.argument(0) else {
+                let persona = args.personas.get(channel).await;
+                let names = args.personas.names().await.join(", ");
+                args.events.publish(BotEvent::SendChat { channel: envelope.channel.clone(), text: format!("Current persona: {}. Available: {}", persona.name, names) });
+                return true;
+            };
+            if permission < Permission::Moderator {
+                println!("[COMMAND] {} is not allowed to use !{}", sender, command.name);
+                return true;
+            }
+            let persona = match args.personas.switch(channel, name).await {
+                Ok(persona) => persona,
+                Err(err) => {
+                    args.events.publish(BotEvent::SendChat { channel: envelope.channel.clone(), text: format!("@{} {}", sender, err) });
+                    return true;
+                }
+            };
+            if args.personas.reset_history_on_switch().await {
+                args.events.publish(BotEvent::LlmRequest(LlmRequest::new(sender, "", LlmRequestKind::ResetHistory).with_envelope(envelope.clone())));
+            }
+            if let Some(locale) = &persona.tts_locale {
+                args.events.publish(BotEvent::TtsControl(TtsControl::SetVoice {
+                    locale: locale.clone(),
+                    gender: persona.tts_gender.clone(),
+                }));
+            }
+            args.events.publish(BotEvent::SendChat { channel: envelope.channel.clone(), text: format!("Persona switched to {}", persona.name) });
+            true
+        }
+        "myvoice" => {
+            let user = sender.clone();
+            let user_id = envelope.author.user_id.clone();
+            let control = match command.argument(0) {
+                None => TtsControl::UserVoiceInfo { user, user_id },
+                Some("reset") => TtsControl::SetUserVoice { user, user_id, voice: None },
+                Some(voice) => TtsControl::SetUserVoice { user, user_id, voice: Some(voice.to_string()) },
+            };
+            args.events.publish(BotEvent::TtsControl(control));
+            true
+        }
+        "tts" => {
+            if permission < Permission::Moderator {
+                println!("[COMMAND] {} is not allowed to use !{}", sender, command.name);
+                return true;
+            }
+            let control = match command.argument(0) {
+                Some("skip") => TtsControl::Skip,
+                Some("pause") => TtsControl::Pause,
+                Some("resume") => TtsControl::Resume,
+                Some("clear") => TtsControl::Clear,
+                _ => TtsControl::Status,
+            };
+            args.events.publish(BotEvent::TtsControl(control));
+            true
+        }
+        "queue" => {
+            args.events.publish(BotEvent::LlmRequest(LlmRequest::new(sender, "", LlmRequestKind::QueuePosition).with_envelope(envelope.clone())));
+            true
+        }
+        _ => false,
+    }
+}
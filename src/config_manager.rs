@@ -18,9 +18,7 @@ pub trait ConfigManager {
         match config_content {
             Ok(config) => {
                 match toml::from_str(&config) {
-                    Ok(config) => {
-                        return Ok(config);
-                    }
+                    Ok(config) => Ok(config),
                     Err(err) => {
                         println!(
                             "Failed to parse config file: {:#?}. Using default config",
@@ -29,7 +27,7 @@ pub trait ConfigManager {
                         println!(
                             "Please check the config file: {config_file_name}, delete it and restart the program, or fix the error in the file"
                         );
-                        return Ok(serde_json::from_value(serde_json::to_value(default_config)?)?);
+                        Ok(serde_json::from_value(serde_json::to_value(default_config)?)?)
                     }
                 }
             }
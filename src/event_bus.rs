@@ -0,0 +1,170 @@
+// Typed publish/subscribe between the bot modules, so producers never know who consumes their events
+#![allow(dead_code)]
+
+use std::sync::{ Arc, RwLock, Weak };
+
+use crate::chat_envelope::ChatEnvelope;
+use crate::colors::Colorize;
+use crate::com::{ MessageQueue, QueueMetrics, QueuePolicy };
+use crate::ollama::LlmRequest;
+use crate::tts::TtsControl;
+
+/// Events a subscriber can fall behind by before the oldest ones are dropped
+pub const DEFAULT_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Topic {
+    Chat,
+    Alert,
+    LlmRequest,
+    LlmReply,
+    TtsControl,
+    SendChat,
+}
+
+#[derive(Debug, Clone)]
+pub enum BotEvent {
+    /// Chat message that is not a bot command
+    Chat(ChatEnvelope),
+    /// Channel notice, e.g. subscriptions and raids
+    Alert(ChatEnvelope),
+    /// Request to the LLM that does not come from a chat message, e.g. from a command
+    LlmRequest(LlmRequest),
+    /// Answer of the LLM, `text` as filtered for the chat, `speech` with the speech markup
+    LlmReply { bot_name: String, text: String, speech: String, reply_to: Option<ChatEnvelope> },
+    TtsControl(TtsControl),
+    /// Text to send to the chat of `channel` as is
+    SendChat { channel: String, text: String },
+}
+
+impl BotEvent {
+    pub fn topic(&self) -> Topic {
+        match self {
+            BotEvent::Chat(_) => Topic::Chat,
+            BotEvent::Alert(_) => Topic::Alert,
+            BotEvent::LlmRequest(_) => Topic::LlmRequest,
+            BotEvent::LlmReply { .. } => Topic::LlmReply,
+            BotEvent::TtsControl(_) => Topic::TtsControl,
+            BotEvent::SendChat { .. } => Topic::SendChat,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Subscription {
+    name: String,
+    topics: Vec<Topic>,
+    /// Events published since this subscriber last read, its own cursor on the bus
+    queue: MessageQueue<Arc<BotEvent>>,
+}
+
+/// Receives the events of the topics it subscribed to, unsubscribes when dropped
+#[derive(Debug)]
+pub struct Subscriber {
+    subscription: Arc<Subscription>,
+}
+
+impl Subscriber {
+    pub async fn recv(&self) -> Arc<BotEvent> {
+        self.subscription.queue.recv().await
+    }
+
+    pub fn try_recv(&self) -> Option<Arc<BotEvent>> {
+        self.subscription.queue.try_recv()
+    }
+
+    pub fn name(&self) -> &str {
+        &self.subscription.name
+    }
+
+    pub fn metrics(&self) -> QueueMetrics {
+        self.subscription.queue.metrics()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct EventBus {
+    subscriptions: RwLock<Vec<Weak<Subscription>>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        EventBus::default()
+    }
+
+    /// Subscriber to `topics`, receiving only what is published from now on.
+    /// When it falls behind its oldest events are dropped.
+    pub fn subscribe(&self, name: &str, topics: &[Topic]) -> Subscriber {
+        self.subscribe_with(name, topics, DEFAULT_CAPACITY, QueuePolicy::DropOldest)
+    }
+
+    /// Subscriber holding up to `capacity` unread events, `overflow` decides which one is lost past it.
+    /// Publishing never waits, so Block and Error refuse the new event like DropNewest.
+    /// Events that must not be lost behind a chat burst, e.g. controls, want a subscription of their own.
+    pub fn subscribe_with(&self, name: &str, topics: &[Topic], capacity: usize, overflow: QueuePolicy) -> Subscriber {
+        let subscription = Arc::new(Subscription {
+            name: name.to_string(),
+            topics: topics.to_vec(),
+            queue: MessageQueue::bounded(capacity, overflow),
+        });
+        self.subscriptions
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push(Arc::downgrade(&subscription));
+        Subscriber { subscription }
+    }
+
+    /// Delivers `event` to every subscriber of its topic, returns how many queued it.
+    /// Events lost because a subscriber is full are reported.
+    pub fn publish(&self, event: BotEvent) -> usize {
+        let topic = event.topic();
+        let event = Arc::new(event);
+        let mut delivered = 0;
+        let mut closed = false;
+        for subscription in self.subscriptions
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .iter() {
+            let Some(subscription) = subscription.upgrade() else {
+                closed = true;
+                continue;
+            };
+            if !subscription.topics.contains(&topic) {
+                continue;
+            }
+            match subscription.queue.try_send_evicting(event.clone()) {
+                Ok(None) => {
+                    delivered += 1;
+                }
+                Ok(Some(evicted)) => {
+                    delivered += 1;
+                    println!("{} {} is full, dropped an older {:?}", "[BUS]".red(), subscription.name, evicted.topic());
+                }
+                Err(_) => {
+                    println!("{} {} is full, dropped {:?}", "[BUS]".red(), subscription.name, topic);
+                }
+            }
+        }
+        if closed {
+            self.subscriptions
+                .write()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .retain(|subscription| subscription.strong_count() > 0);
+        }
+        if delivered == 0 {
+            println!("[BUS] No subscriber for {:?}", topic);
+        }
+        delivered
+    }
+
+    /// Name and queue metrics of every live subscriber
+    pub fn metrics(&self) -> Vec<(String, QueueMetrics)> {
+        self.subscriptions
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .iter()
+            .filter_map(|subscription| subscription.upgrade())
+            .map(|subscription| (subscription.name.clone(), subscription.queue.metrics()))
+            .collect()
+    }
+}
@@ -0,0 +1,120 @@
+// Twitch Helix API, for the moderation actions chat commands can no longer do
+#![allow(dead_code)]
+
+use std::{ collections::HashMap, time::{ Duration, Instant } };
+
+use anyhow::{ anyhow, Result };
+use chrono::{ DateTime, Local };
+use reqwest::Method;
+use serde_json::{ json, Value };
+use tokio::sync::{ Mutex, RwLock };
+
+use crate::colors::Colorize;
+
+const HELIX_URL: &str = "https://api.twitch.tv/helix";
+/// The stream start is asked again after this, it is needed by every prompt
+const STREAM_CACHE_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Default)]
+struct Credentials {
+    client_id: String,
+    /// User access token of the bot, without the "oauth:" prefix
+    token: String,
+}
+
+/// When the stream start was asked, and the answer
+type StreamStart = (Instant, Option<DateTime<Local>>);
+
+#[derive(Debug, Default)]
+pub struct HelixClient {
+    http: reqwest::Client,
+    credentials: RwLock<Option<Credentials>>,
+    /// Start of the live stream by channel login, None if offline
+    stream_starts: Mutex<HashMap<String, StreamStart>>,
+}
+
+impl HelixClient {
+    pub fn new() -> Self {
+        HelixClient::default()
+    }
+
+    /// Enables the API, the token needs the moderator:manage:banned_users scope for timeouts
+    pub async fn set_credentials(&self, client_id: &str, token: &str) {
+        *self.credentials.write().await = (!client_id.is_empty()).then(|| Credentials {
+            client_id: client_id.to_string(),
+            token: token.trim_start_matches("oauth:").to_string(),
+        });
+    }
+
+    async fn request(&self, method: Method, path: &str, query: &[(&str, &str)], body: Option<Value>) -> Result<Value> {
+        let credentials = self.credentials
+            .read().await
+            .clone()
+            .ok_or_else(|| anyhow!("the Twitch API client id is not configured"))?;
+        let mut request = self.http
+            .request(method, format!("{}/{}", HELIX_URL, path))
+            .query(query)
+            .header("Client-Id", credentials.client_id)
+            .bearer_auth(credentials.token);
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        let text = response.text().await?;
+        if !status.is_success() {
+            return Err(anyhow!("Twitch API {} answered {}: {}", path, status, text));
+        }
+        Ok(serde_json::from_str(&text)?)
+    }
+
+    pub async fn user_id(&self, login: &str) -> Result<String> {
+        let users = self.request(Method::GET, "users", &[("login", login)], None).await?;
+        users["data"][0]["id"]
+            .as_str()
+            .map(|id| id.to_string())
+            .ok_or_else(|| anyhow!("no Twitch user {}", login))
+    }
+
+    /// When the stream of `login` went live, None if it is offline or the API can not be reached
+    pub async fn stream_started_at(&self, login: &str) -> Option<DateTime<Local>> {
+        let mut stream_starts = self.stream_starts.lock().await;
+        if let Some((asked_at, started_at)) = stream_starts.get(login) {
+            if asked_at.elapsed() < STREAM_CACHE_TTL {
+                return *started_at;
+            }
+        }
+        // Failures are cached too, the API is not asked again for every prompt
+        let started_at = match self.request(Method::GET, "streams", &[("user_login", login)], None).await {
+            Ok(streams) =>
+                streams["data"][0]["started_at"]
+                    .as_str()
+                    .and_then(|started_at| DateTime::parse_from_rfc3339(started_at).ok())
+                    .map(|started_at| started_at.with_timezone(&Local)),
+            Err(err) => {
+                println!("{} Stream of {} unknown: {}", "[HELIX]".red(), login, err);
+                None
+            }
+        };
+        stream_starts.insert(login.to_string(), (Instant::now(), started_at));
+        started_at
+    }
+
+    pub async fn timeout(
+        &self,
+        broadcaster_id: &str,
+        moderator_id: &str,
+        user_id: &str,
+        seconds: u64,
+        reason: &str
+    ) -> Result<()> {
+        let body = json!({ "data": { "user_id": user_id, "duration": seconds, "reason": reason } });
+        self.request(
+            Method::POST,
+            "moderation/bans",
+            &[("broadcaster_id", broadcaster_id), ("moderator_id", moderator_id)],
+            Some(body)
+        ).await?;
+        Ok(())
+    }
+}
@@ -24,6 +24,14 @@ impl IrcMessage {
             payload: payload.into(),
         }
     }
+
+    /// Returns the value of a twitch tag, None if missing or empty
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.token
+            .get(key)
+            .map(|value| value.as_str())
+            .filter(|value| !value.is_empty())
+    }
 }
 
 #[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
@@ -72,18 +80,12 @@ pub fn parse_message(msg: &String) -> IrcMessage {
         _ => {
             token = "";
             msg_arr = msg.splitn(2, ':').collect::<Vec<&str>>();
-            context = msg_arr.get(0).unwrap_or(&"").trim();
+            context = msg_arr.first().unwrap_or(&"").trim();
             payload = msg_arr.get(1).unwrap_or(&"").trim();
         }
     }
 
-    let irc_message = IrcMessage::new(
-        parse_irc_message_token(token),
-        parse_irc_message_context(context),
-        payload
-    );
-
-    irc_message
+    IrcMessage::new(parse_irc_message_token(token), parse_irc_message_context(context), payload)
 }
 
 fn parse_irc_message_context(context: &str) -> Context {
@@ -94,12 +96,15 @@ fn parse_irc_message_context(context: &str) -> Context {
             ..Context::default()
         };
     }
-    let sender_full = context_arr.get(0).unwrap_or(&"").trim();
-    let command = context_arr[1..context_arr.len() - 1].join(" ");
-    let destination = context_arr.last().unwrap_or(&"").trim();
+    let sender_full = context_arr.first().unwrap_or(&"").trim();
+    // "tmi.twitch.tv GLOBALUSERSTATE" has a command but no destination
+    let (command, destination) = match context_arr.len() {
+        2 => (context_arr[1].to_string(), ""),
+        _ => (context_arr[1..context_arr.len() - 1].join(" "), context_arr.last().unwrap_or(&"").trim()),
+    };
 
     let sender = if sender_full.contains('!') {
-        sender_full.split('!').collect::<Vec<&str>>().get(0).unwrap_or(&"").to_string()
+        sender_full.split('!').collect::<Vec<&str>>().first().unwrap_or(&"").to_string()
     } else {
         sender_full.to_string()
     };
@@ -118,3 +123,25 @@ fn parse_irc_message_token(token: &str) -> HashMap<String, String> {
         })
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globaluserstate_has_its_command() {
+        let message = parse_message(
+            &"@badge-info=;badges=;color=#0000FF;display-name=BoTOX;emote-sets=0;user-id=123456789;user-type= :tmi.twitch.tv GLOBALUSERSTATE".to_string()
+        );
+        assert_eq!(message.context.command, "GLOBALUSERSTATE");
+        assert_eq!(message.context.destination, "");
+        assert_eq!(message.tag("user-id"), Some("123456789"));
+    }
+
+    #[test]
+    fn privmsg_keeps_sender_command_and_destination() {
+        let message = parse_message(&"@id=1 :alice!alice@alice.tmi.twitch.tv PRIVMSG #channel :hello there".to_string());
+        assert_eq!(message.context, Context::new("alice", "PRIVMSG", "#channel"));
+        assert_eq!(message.payload, "hello there");
+    }
+}
@@ -0,0 +1,136 @@
+// Offline language identification, comparing character trigram profiles
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+
+/// Sample text of each supported language, made of its most common words
+const LANGUAGE_SAMPLES: &[(&str, &str)] = &[
+    (
+        "en",
+        "the and you that was for are with his they this have from one had word but not what all \
+        were when your can said there use each which she how their will other about out many then \
+        them these some her would make like him into time has look two more write see number way \
+        could people than first water been call who now find long down day did get come made may \
+        hello thanks what are you doing today how is it going good game stream play i think this is",
+    ),
+    (
+        "it",
+        "il di che non la per un sono una mi ma ho lo ha le si ti con cosa se io come da ci questo \
+        qui hai bene sei del tu no solo mio tutto me era gli lui della anche ciao grazie sto bene e \
+        tu oggi come stai che fai questa partita gioco bello molto perché quando dove sempre ancora \
+        voglio fare andiamo allora proprio niente adesso dopo prima siamo sono stato",
+    ),
+    (
+        "es",
+        "de que no a la el es y en lo un por qué me una te los se con para mi está si bien pero yo \
+        eso las sí su tu aquí del al como le más esto ya todo esta vamos muy hay ahora algo estoy \
+        tengo nos tú nada cuando ha este sé estás así puedo cómo quiero hola gracias juego partida \
+        hoy bueno también porque donde siempre todavía hacer",
+    ),
+    (
+        "fr",
+        "de la le et les des en un du une que est pour qui dans par plus pas au sur ne se ce il sont \
+        avec ils je tu nous vous elle mais ou comme tout bien aussi très fait être avoir merci \
+        bonjour salut comment ça va aujourd'hui jeu partie pourquoi quand toujours encore faire \
+        veux peux c'est je suis on est beaucoup rien maintenant",
+    ),
+    (
+        "de",
+        "der die und in den von zu das mit sich des auf für ist im dem nicht ein eine als auch es an \
+        werden aus er hat dass sie nach wird bei einer um am sind noch wie einem über einen so zum \
+        war haben nur oder aber vor zur bis mehr durch man ich du wir ihr hallo danke wie geht es \
+        heute spiel warum wann immer noch machen will kann sehr gut jetzt nichts",
+    ),
+    (
+        "pt",
+        "de que não o a do da em um para é com uma os no se na por mais as dos como mas foi ao ele \
+        das tem à seu sua ou ser quando muito há nos já está eu também só pelo pela até isso ela \
+        entre era depois sem mesmo aos ter seus quem nas me esse eles estão você olá obrigado jogo \
+        hoje bom porque onde sempre ainda fazer quero posso agora nada",
+    ),
+];
+
+fn trigrams(text: &str) -> HashMap<String, f32> {
+    let mut counts = HashMap::new();
+    for word in text.to_lowercase().split(|c: char| !c.is_alphabetic() && c != '\'') {
+        if word.is_empty() {
+            continue;
+        }
+        let chars = format!(" {} ", word).chars().collect::<Vec<char>>();
+        for window in chars.windows(3) {
+            *counts.entry(window.iter().collect::<String>()).or_insert(0.0) += 1.0;
+        }
+    }
+    counts
+}
+
+fn norm(profile: &HashMap<String, f32>) -> f32 {
+    profile
+        .values()
+        .map(|count| count * count)
+        .sum::<f32>()
+        .sqrt()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DetectedLanguage {
+    /// ISO 639-1 code, e.g. "it"
+    pub language: String,
+    /// Cosine similarity with the language profile, 0.0 - 1.0
+    pub confidence: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct LanguageDetector {
+    profiles: Vec<(String, HashMap<String, f32>, f32)>,
+}
+
+impl Default for LanguageDetector {
+    fn default() -> Self {
+        LanguageDetector {
+            profiles: LANGUAGE_SAMPLES.iter()
+                .map(|(language, sample)| {
+                    let profile = trigrams(sample);
+                    let norm = norm(&profile);
+                    (language.to_string(), profile, norm)
+                })
+                .collect(),
+        }
+    }
+}
+
+impl LanguageDetector {
+    pub fn new() -> Self {
+        LanguageDetector::default()
+    }
+
+    pub fn languages(&self) -> Vec<&str> {
+        self.profiles
+            .iter()
+            .map(|(language, _, _)| language.as_str())
+            .collect()
+    }
+
+    /// Most likely language of `text`, None if the text has no letters
+    pub fn detect(&self, text: &str) -> Option<DetectedLanguage> {
+        let input = trigrams(text);
+        let input_norm = norm(&input);
+        if input_norm == 0.0 {
+            return None;
+        }
+
+        self.profiles
+            .iter()
+            .map(|(language, profile, profile_norm)| {
+                let dot = input
+                    .iter()
+                    .filter_map(|(trigram, count)| profile.get(trigram).map(|weight| count * weight))
+                    .sum::<f32>();
+                DetectedLanguage {
+                    language: language.clone(),
+                    confidence: dot / (input_norm * profile_norm),
+                }
+            })
+            .max_by(|a, b| a.confidence.total_cmp(&b.confidence))
+    }
+}
@@ -1,7 +1,13 @@
 #![allow(dead_code)]
-use std::sync::Arc;
+use std::{ future::Future, sync::Arc };
 
-use com::MessageQueue;
+use colors::Colorize;
+
+use chat_state::ChatState;
+use event_bus::EventBus;
+use helix::HelixClient;
+use config_manager::ConfigManager;
+use personas::{ PersonaConfig, Personas };
 use tokio::sync::RwLock;
 
 mod config_manager;
@@ -11,11 +17,37 @@ mod colors;
 mod ollama;
 mod com;
 mod tts;
+mod trigger;
+mod chat_state;
+mod tools;
+mod memory;
+mod chat_history;
+mod commands;
+mod scheduler;
+mod reply_filter;
+mod templates;
+mod personas;
+mod lang_detect;
+mod audio_sink;
+mod overlay;
+mod voice_assign;
+mod ssml;
+mod tts_normalize;
+mod tts_playback;
+mod audio_cache;
+mod tts_engine;
+mod voice_list;
+mod voice_query;
+mod event_bus;
+mod chat_envelope;
+mod helix;
 
 #[derive(Debug, Clone, Default)]
 pub struct BOTInfo {
     name: Arc<RwLock<String>>,
     main_channel: Arc<RwLock<String>>,
+    /// Twitch user id of the bot, empty until GLOBALUSERSTATE
+    user_id: Arc<RwLock<String>>,
 }
 
 impl BOTInfo {
@@ -27,6 +59,10 @@ impl BOTInfo {
         *self.main_channel.write().await = main_channel.to_string();
     }
 
+    pub async fn set_user_id(&self, user_id: &str) {
+        *self.user_id.write().await = user_id.to_string();
+    }
+
     pub async fn get_name(&self) -> String {
         self.name.read().await.clone()
     }
@@ -34,35 +70,44 @@ impl BOTInfo {
     pub async fn get_main_channel(&self) -> String {
         self.main_channel.read().await.clone()
     }
+
+    pub async fn get_user_id(&self) -> String {
+        self.user_id.read().await.clone()
+    }
 }
 
 struct Args {
     bot_info: BOTInfo,
-    twitch_queue: MessageQueue<String>,
-    ollama: MessageQueue<String>,
-    tts_message_queue: MessageQueue<String>,
+    chat_state: ChatState,
+    personas: Personas,
+    events: EventBus,
+    helix: HelixClient,
 }
 
 #[tokio::main]
 async fn main() {
     let bot_info = BOTInfo::default();
+    let persona_config = PersonaConfig::load_config::<PersonaConfig>(
+        PersonaConfig::default(),
+        "persona_config.toml"
+    ).await.unwrap();
 
     let args = Arc::new(Args {
         bot_info,
-        ollama: MessageQueue::new(),
-        twitch_queue: MessageQueue::new(),
-        tts_message_queue: MessageQueue::new(),
+        chat_state: ChatState::default(),
+        personas: Personas::new(persona_config),
+        events: EventBus::new(),
+        helix: HelixClient::new(),
     });
 
+    // The consumers subscribe to the bus as soon as they start, before the chat comes in
     let tasks = vec![
-        // tokio::spawn(twitch_client::start(args.clone())),
-        // tokio::spawn(ollama::start(args.clone())),
-        tokio::spawn(tts::start(args.clone()))
+        tokio::spawn(report_failure("LLM", ollama::start(args.clone()))),
+        tokio::spawn(report_failure("TTS", tts::start(args.clone())))
     ];
 
     let mut tokio_handles = Vec::new();
-    tokio_handles.push(tokio::spawn(twitch_client::start(args.clone())));
-    // tokio_handles.push(tokio::spawn(ollama::start(args.clone())));
+    tokio_handles.push(tokio::spawn(report_failure("Twitch client", twitch_client::start(args.clone()))));
 
     for task in tasks {
         tokio_handles.push(task);
@@ -72,3 +117,12 @@ async fn main() {
         handle.await.unwrap().unwrap();
     }
 }
+
+/// Runs a module of the bot, logging its failure when it happens rather than when it is awaited
+async fn report_failure(name: &str, module: impl Future<Output = anyhow::Result<()>>) -> anyhow::Result<()> {
+    let result = module.await;
+    if let Err(err) = &result {
+        println!("{} {} stopped: {:#}", "[ERROR]".red(), name, err);
+    }
+    result
+}
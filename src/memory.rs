@@ -0,0 +1,291 @@
+// Long term memory: chat lines stored with their embeddings in a local index file
+#![allow(dead_code)]
+
+use std::{ sync::Arc, time::Duration };
+
+use anyhow::{ anyhow, Result };
+use chrono::Local;
+use ollama_rs::{ generation::embeddings::request::GenerateEmbeddingsRequest, Ollama };
+use serde::{ Deserialize, Serialize };
+use tokio::sync::RwLock;
+
+use crate::colors::Colorize;
+use crate::com::{ MessageQueue, QueuePolicy };
+use crate::config_manager::ConfigManager;
+
+/// Chat lines waiting for their embedding, the oldest are dropped past it
+const PENDING_LINES: usize = 256;
+/// The index is written at most this often
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MemoryConfig {
+    pub enabled: bool,
+    /// Ollama model used to compute the embeddings
+    pub embedding_model: String,
+    /// JSON file holding the memories and their embeddings
+    pub index_file: String,
+    /// Maximum number of memories of the asking chatter injected in each prompt
+    pub top_k: usize,
+    /// Memories less similar than this (cosine, -1.0 - 1.0) are never recalled
+    pub min_similarity: f32,
+    /// Chat lines shorter than this are not worth remembering
+    pub min_text_length: usize,
+    /// Oldest memories are forgotten above this size
+    pub max_entries: usize,
+}
+
+impl ConfigManager for MemoryConfig {}
+
+impl Default for MemoryConfig {
+    fn default() -> Self {
+        MemoryConfig {
+            enabled: true,
+            embedding_model: "nomic-embed-text".into(),
+            index_file: "memory_index.json".into(),
+            top_k: 5,
+            min_similarity: 0.5,
+            min_text_length: 20,
+            max_entries: 5000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MemoryEntry {
+    pub user: String,
+    pub text: String,
+    pub created_at: String,
+    pub embedding: Vec<f32>,
+}
+
+/// Chat line waiting to be embedded
+#[derive(Debug, Clone)]
+struct MemoryLine {
+    user: String,
+    text: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct MemoryStore {
+    config: MemoryConfig,
+    entries: Vec<MemoryEntry>,
+    /// Entries changed since the last save
+    dirty: bool,
+}
+
+impl MemoryStore {
+    /// Loads the index file, starting with an empty memory if it does not exist yet
+    pub fn load(config: MemoryConfig) -> Result<Self> {
+        let entries = match std::fs::read_to_string(&config.index_file) {
+            Ok(content) => serde_json::from_str(&content)?,
+            Err(_) => Vec::new(),
+        };
+        println!("[MEMORY] Loaded {} memories from {}", entries.len(), config.index_file);
+        Ok(MemoryStore { config, entries, dirty: false })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// A chat line worth remembering
+    fn accepts(&self, text: &str) -> bool {
+        self.config.enabled && text.chars().count() >= self.config.min_text_length
+    }
+
+    fn insert(&mut self, entry: MemoryEntry) {
+        self.entries.push(entry);
+        if self.entries.len() > self.config.max_entries {
+            let overflow = self.entries.len() - self.config.max_entries;
+            self.entries.drain(..overflow);
+        }
+        self.dirty = true;
+    }
+
+    /// Entries to save if they changed since the last call
+    fn take_changes(&mut self) -> Option<Vec<MemoryEntry>> {
+        if !self.dirty {
+            return None;
+        }
+        self.dirty = false;
+        Some(self.entries.clone())
+    }
+
+    /// The `top_k` memories of `user` most similar to the `query` embedding, most relevant first
+    fn recall(&self, user: &str, query: &[f32]) -> Vec<&MemoryEntry> {
+        let mut scored = self.entries
+            .iter()
+            .filter(|entry| entry.user.eq_ignore_ascii_case(user))
+            .map(|entry| (cosine_similarity(query, &entry.embedding), entry))
+            .filter(|(score, _)| *score >= self.config.min_similarity)
+            .collect::<Vec<_>>();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        scored
+            .into_iter()
+            .take(self.config.top_k)
+            .map(|(_, entry)| entry)
+            .collect()
+    }
+}
+
+async fn embed(ollama: &Ollama, model: &str, text: &str) -> Result<Vec<f32>> {
+    let request = GenerateEmbeddingsRequest::new(model.to_string(), text.into());
+    ollama
+        .generate_embeddings(request).await?
+        .embeddings.into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("no embedding returned by {}", model))
+}
+
+/// Memory shared by the LLM workers. The chat lines are embedded and the index is saved in the
+/// background, so a busy chat never waits on the embedder or the disk.
+#[derive(Debug, Clone)]
+pub struct Memory {
+    store: Arc<RwLock<MemoryStore>>,
+    lines: Arc<MessageQueue<MemoryLine>>,
+    ollama: Ollama,
+    config: MemoryConfig,
+}
+
+impl Memory {
+    /// Starts the background tasks embedding the lines and saving the index
+    pub fn start(store: MemoryStore, ollama: Ollama) -> Self {
+        let memory = Memory {
+            config: store.config.clone(),
+            store: Arc::new(RwLock::new(store)),
+            lines: Arc::new(MessageQueue::bounded(PENDING_LINES, QueuePolicy::DropOldest)),
+            ollama,
+        };
+        tokio::spawn(memory.clone().embed_lines());
+        tokio::spawn(memory.clone().save_changes());
+        memory
+    }
+
+    async fn embed_lines(self) {
+        loop {
+            let line = self.lines.recv().await;
+            let embedding = match embed(&self.ollama, &self.config.embedding_model, &line.text).await {
+                Ok(embedding) => embedding,
+                Err(err) => {
+                    println!("{} Failed to store memory: {}", "[MEMORY]".red(), err);
+                    continue;
+                }
+            };
+            self.store.write().await.insert(MemoryEntry {
+                user: line.user,
+                text: line.text,
+                created_at: Local::now().format("%Y-%m-%d").to_string(),
+                embedding,
+            });
+        }
+    }
+
+    async fn save_changes(self) {
+        let mut interval = tokio::time::interval(SAVE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let Some(entries) = self.store.write().await.take_changes() else {
+                continue;
+            };
+            let result = match serde_json::to_string(&entries) {
+                Ok(content) => tokio::fs::write(&self.config.index_file, content).await.map_err(|err| err.into()),
+                Err(err) => Err(anyhow::Error::from(err)),
+            };
+            if let Err(err) = result {
+                println!("{} Failed to save {}: {}", "[MEMORY]".red(), self.config.index_file, err);
+            }
+        }
+    }
+
+    /// Queues a chat line of `user`, if it is long enough to be notable
+    pub async fn remember(&self, user: &str, text: &str) {
+        if !self.store.read().await.accepts(text) {
+            return;
+        }
+        _ = self.lines.try_send(MemoryLine { user: user.to_string(), text: text.to_string() });
+    }
+
+    /// System prompt section with the memories of `user` related to `query`, None if there are none
+    pub async fn system_prompt(&self, user: &str, query: &str) -> Option<String> {
+        if !self.config.enabled || self.store.read().await.is_empty() {
+            return None;
+        }
+        let query = match embed(&self.ollama, &self.config.embedding_model, query).await {
+            Ok(query) => query,
+            Err(err) => {
+                println!("{} Failed to recall memories: {}", "[MEMORY]".red(), err);
+                return None;
+            }
+        };
+        let lines = self.store
+            .read().await
+            .recall(user, &query)
+            .iter()
+            .map(|memory| format!("- ({}): {}", memory.created_at, memory.text))
+            .collect::<Vec<String>>();
+        if lines.is_empty() {
+            return None;
+        }
+        Some(format!("Things you remember {} told you in past conversations:\n{}", user, lines.join("\n")))
+    }
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return -1.0;
+    }
+    let dot = a
+        .iter()
+        .zip(b)
+        .map(|(x, y)| x * y)
+        .sum::<f32>();
+    let norm_a = a
+        .iter()
+        .map(|x| x * x)
+        .sum::<f32>()
+        .sqrt();
+    let norm_b = b
+        .iter()
+        .map(|x| x * x)
+        .sum::<f32>()
+        .sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return -1.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(user: &str, text: &str, embedding: Vec<f32>) -> MemoryEntry {
+        MemoryEntry { user: user.into(), text: text.into(), created_at: "2024-01-01".into(), embedding }
+    }
+
+    #[test]
+    fn recall_only_returns_the_memories_of_the_asker() {
+        let mut store = MemoryStore { config: MemoryConfig::default(), entries: Vec::new(), dirty: false };
+        store.insert(entry("alice", "my cat is called Tom", vec![1.0, 0.0]));
+        store.insert(entry("bob", "my cat is called Felix", vec![1.0, 0.0]));
+
+        let recalled = store.recall("Alice", &[1.0, 0.0]);
+        assert_eq!(recalled.len(), 1);
+        assert_eq!(recalled[0].text, "my cat is called Tom");
+        assert!(store.recall("carol", &[1.0, 0.0]).is_empty());
+    }
+
+    #[test]
+    fn changes_are_saved_once() {
+        let mut store = MemoryStore { config: MemoryConfig::default(), entries: Vec::new(), dirty: false };
+        assert!(store.take_changes().is_none());
+        store.insert(entry("alice", "my cat is called Tom", vec![1.0]));
+        assert_eq!(store.take_changes().map(|entries| entries.len()), Some(1));
+        assert!(store.take_changes().is_none());
+    }
+}
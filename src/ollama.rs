@@ -1,20 +1,39 @@
-use std::sync::Arc;
+use std::{ collections::{ hash_map::Entry, HashMap }, sync::Arc, time::Duration };
 use anyhow::Result;
 use crate::colors::Colorize;
-use ollama_rs::{ generation::completion::request::GenerationRequest, Ollama };
+use ollama_rs::{
+    generation::{ chat::{ request::ChatMessageRequest, ChatMessage }, options::GenerationOptions },
+    Ollama,
+};
+use serde::{ Deserialize, Serialize };
+use tokio::sync::{ Mutex, Semaphore };
 
+use crate::chat_envelope::ChatEnvelope;
+use crate::chat_history::{ self, ChatHistory };
+use crate::com::QueuePolicy;
+use crate::config_manager::ConfigManager;
+use crate::event_bus::{ BotEvent, Topic };
+use crate::memory::{ Memory, MemoryConfig, MemoryStore };
+use crate::personas::Persona;
+use crate::reply_filter::{ FilterContext, ReplyFilterConfig, ReplyFilterPipeline };
+use crate::scheduler::LlmScheduler;
+use crate::ssml;
+use crate::templates::{ self, PromptTemplate, TemplateVariables };
+use crate::tools::{ ToolCall, ToolRegistry };
+use crate::trigger::{ TriggerConfig, TriggerEngine };
 use crate::Args;
 
-pub async fn start(args: Arc<Args>) -> Result<()> {
-    let ollama = Ollama::new("http://127.0.0.1", 6666);
-    let model_name = "llama3.2";
+/// Maximum number of tool calls the model can chain before answering
+const MAX_TOOL_CALLS: usize = 3;
 
-    let prompt =
-        r#"
+/// Written to the persona `system_prompt_file` on first start
+const DEFAULT_SYSTEM_PROMPT: &str =
+    r#"
     **Twitch Chatbot Prompt:**
 
-    You are a chatbot for a Twitch channel, designed to interact with users in real-time. 
-    Your Nickname is BoTOX.
+    You are a chatbot for a Twitch channel, designed to interact with users in real-time.
+    Your Nickname is {{bot_name}}.
+    You are in the channel #{{channel}}, the current time is {{time}}.
     Your input format will be '[nickname]: Message'. Your tasks include:
 
     1. Identify the language of the incoming message from the user.
@@ -31,42 +50,495 @@ pub async fn start(args: Arc<Args>) -> Result<()> {
     **Example Interaction:**
     - Input: "[JohnDoe]: ¿Cómo estás?"
     - Output: "¡Hola, JohnDoe! Estoy bien, gracias. ¿Y tú?"
-    
+
     - Input: "[JaneDoe]: I need help with my game!"
     - Output: "Hey JaneDoe! What game are you playing? I'm here to help!"
-    
+
     - Input: "[UnknownUser]: Je ne comprends pas!"
     - Output: "Sorry, I can understand English, Spanish, French, German, and Portuguese. How can I assist you?"
-    
+
     - Input: "[GenericName]: Ciao come stai oggi?"
     - Output: "Ciao, GenericName! Sto bene, grazie. E tu?"
 
 "#;
 
-    let generation_request = GenerationRequest::new(model_name.into(), "Starting".into()).system(
-        prompt.into()
+/// Written to `OllamaConfig::message_prompt_file` on first start
+const DEFAULT_MESSAGE_PROMPT: &str = "[{{display_name}}]: {{message}}";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LlmRequestKind {
+    /// The message must be answered
+    Reply,
+    /// The message is only added to the conversation history
+    Context,
+    /// Send the channel conversation summary to the chat
+    ShowSummary,
+    /// Forget the channel conversation summary
+    ResetSummary,
+    /// Tell the sender the position of their question in the queue
+    QueuePosition,
+    /// Forget the channel conversation, e.g. after a persona switch
+    ResetHistory,
+}
+
+#[derive(Debug, Clone)]
+pub struct LlmRequest {
+    pub sender: String,
+    /// Message text, rendered with the message prompt template before reaching the model
+    pub prompt: String,
+    pub kind: LlmRequestKind,
+    /// Metadata of the chat message available to the templates
+    pub variables: TemplateVariables,
+    /// Chat message the request comes from, None if the bot asked itself
+    pub envelope: Option<ChatEnvelope>,
+}
+
+impl LlmRequest {
+    pub fn new(sender: impl Into<String>, prompt: impl Into<String>, kind: LlmRequestKind) -> Self {
+        LlmRequest {
+            sender: sender.into(),
+            prompt: prompt.into(),
+            kind,
+            variables: TemplateVariables::new(),
+            envelope: None,
+        }
+    }
+
+    pub fn with_envelope(mut self, envelope: ChatEnvelope) -> Self {
+        self.envelope = Some(envelope);
+        self
+    }
+
+    /// Channel the request comes from, `default` if the bot asked itself
+    pub fn channel_or(&self, default: &str) -> String {
+        self.envelope
+            .as_ref()
+            .map(|envelope| envelope.channel.clone())
+            .unwrap_or(default.to_string())
+    }
+
+    pub fn with_variables(mut self, variables: TemplateVariables) -> Self {
+        self.variables = variables;
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OllamaConfig {
+    pub host: String,
+    pub port: u16,
+    pub model: String,
+    /// Context window size requested to the model, in tokens
+    pub num_ctx: u32,
+    /// Older turns are summarized when a request, system prompts and memories included, gets bigger than this, in tokens
+    pub summary_token_budget: usize,
+    /// Number of most recent turns never summarized
+    pub summary_keep_recent: usize,
+    /// Number of replies generated at the same time
+    pub max_concurrent_requests: usize,
+    /// Seconds after which an unanswered question is dropped
+    pub request_ttl: u64,
+    /// Answer all the queued questions of a user with a single reply
+    pub merge_user_requests: bool,
+    /// Template of each chat message sent to the model
+    pub message_prompt_file: String,
+}
+
+impl ConfigManager for OllamaConfig {}
+
+impl Default for OllamaConfig {
+    fn default() -> Self {
+        OllamaConfig {
+            host: "http://127.0.0.1".into(),
+            port: 6666,
+            model: "llama3.2".into(),
+            num_ctx: 4096,
+            summary_token_budget: 3000,
+            summary_keep_recent: 10,
+            max_concurrent_requests: 2,
+            request_ttl: 120,
+            merge_user_requests: true,
+            message_prompt_file: "message_prompt.txt".into(),
+        }
+    }
+}
+
+const SUMMARY_PROMPT: &str =
+    "Summarize the following Twitch chat conversation in a few sentences. \
+    Keep who said what, the languages used, open questions and facts about the chatters. \
+    Answer only with the summary.";
+
+/// Condenses the `oldest` turns of a history, and its previous summary, in a new summary
+async fn summarize(
+    ollama: &Ollama,
+    config: &OllamaConfig,
+    previous: Option<String>,
+    oldest: &[ChatMessage]
+) -> Result<String> {
+    let mut conversation = String::new();
+    if let Some(summary) = previous {
+        conversation.push_str(&format!("Previous summary: {}\n", summary));
+    }
+    for message in oldest {
+        conversation.push_str(&message.content);
+        conversation.push('\n');
+    }
+
+    let request = ChatMessageRequest::new(config.model.clone(), vec![
+        ChatMessage::system(SUMMARY_PROMPT.into()),
+        ChatMessage::user(conversation)
+    ]);
+    let response = ollama.send_chat_messages(request).await?;
+    let summary = response.message.map(|message| message.content).unwrap_or_default();
+    println!("{}{} {}", "[AI]".orange(), "[SUMMARY]".blue(), summary);
+    Ok(summary)
+}
+
+struct LlmWorker {
+    args: Arc<Args>,
+    ollama: Ollama,
+    config: OllamaConfig,
+    options: GenerationOptions,
+    tools: ToolRegistry,
+    reply_filter: ReplyFilterPipeline,
+    /// System prompt templates of the personas, by file name
+    system_templates: Mutex<HashMap<String, PromptTemplate>>,
+    message_template: Mutex<PromptTemplate>,
+    memory: Memory,
+    histories: Mutex<HashMap<String, ChatHistory>>,
+}
+
+impl LlmWorker {
+    async fn variables(&self, channel: &str) -> TemplateVariables {
+        templates::global_variables(
+            &self.args.bot_info.get_name().await,
+            channel,
+            self.args.chat_state.started_at(),
+            self.args.helix.stream_started_at(channel).await,
+            &self.args.chat_state.get_recent_chat().await
+        )
+    }
+
+    async fn render_prompt(&self, channel: &str, request: &LlmRequest) -> String {
+        let mut variables = self.variables(channel).await;
+        variables.extend(request.variables.clone());
+        variables.insert("user".into(), request.sender.clone());
+        variables.entry("display_name".into()).or_insert(request.sender.clone());
+        variables.insert("message".into(), request.prompt.clone());
+        self.message_template.lock().await.render(&variables)
+    }
+
+    /// System prompts of the request: the persona template, the tools and the recalled memories
+    async fn system_prompts(
+        &self,
+        channel: &str,
+        persona: &Persona,
+        memories: Option<String>
+    ) -> Result<Vec<String>> {
+        let variables = self.variables(channel).await;
+        let system_prompt = {
+            let mut system_templates = self.system_templates.lock().await;
+            let template = match system_templates.entry(persona.system_prompt_file.clone()) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    entry.insert(PromptTemplate::load(&persona.system_prompt_file, DEFAULT_SYSTEM_PROMPT)?)
+                }
+            };
+            template.render(&variables)
+        };
+        let mut system_prompts = vec![system_prompt, self.tools.system_prompt(), ssml::MARKUP_PROMPT.to_string()];
+        system_prompts.extend(memories);
+        Ok(system_prompts)
+    }
+
+    async fn filter_context(&self) -> FilterContext {
+        FilterContext {
+            bot_name: self.args.bot_info.get_name().await,
+            participants: self.args.chat_state.get_participants().await,
+        }
+    }
+
+    /// Model output ready for the chat, None if the filters reject it
+    async fn chat_text(&self, output: &str) -> Option<String> {
+        let filter_context = self.filter_context().await;
+        self.reply_filter.run(&ssml::strip_markup(output), &filter_context)
+    }
+
+    /// Summarizes the oldest turns of the channel if a request with `system_prompts` and
+    /// `reserved` more tokens would go over the budget. The history stays usable meanwhile.
+    async fn compact(&self, channel: &str, system_prompts: &[String], reserved: usize) {
+        let (previous, oldest) = {
+            let mut histories = self.histories.lock().await;
+            let history = histories.entry(channel.to_string()).or_default();
+            if history.estimated_tokens(system_prompts) + reserved <= self.config.summary_token_budget {
+                return;
+            }
+            (history.summary().map(|summary| summary.to_string()), history.take_oldest(self.config.summary_keep_recent))
+        };
+        if oldest.is_empty() {
+            return;
+        }
+
+        let summary = summarize(&self.ollama, &self.config, previous, &oldest).await;
+        // A history reset in the meantime stays reset
+        let mut histories = self.histories.lock().await;
+        let Some(history) = histories.get_mut(channel) else {
+            return;
+        };
+        match summary {
+            Ok(summary) => history.set_summary(summary),
+            Err(err) => {
+                println!("{}{} Failed to summarize: {}", "[AI]".orange(), "[SUMMARY]".red(), err);
+                history.restore_oldest(oldest);
+            }
+        }
+    }
+
+    async fn add_context(&self, channel: &str, request: LlmRequest) {
+        let prompt = self.render_prompt(channel, &request).await;
+        println!("{}{} Context: {}", "[AI]".orange(), "[RX]".green(), prompt);
+        self.memory.remember(&request.sender, &request.prompt).await;
+        self.histories
+            .lock().await
+            .entry(channel.to_string())
+            .or_default()
+            .push(ChatMessage::user(prompt));
+    }
+
+    /// `compact` for the context only messages, with the prompts of the current persona
+    async fn compact_channel(&self, channel: &str) {
+        let persona = self.args.personas.get(channel).await;
+        match self.system_prompts(channel, &persona, None).await {
+            Ok(system_prompts) => self.compact(channel, &system_prompts, 0).await,
+            Err(err) => println!("{}{} {}", "[AI]".orange(), "[ERROR]".red(), err),
+        }
+    }
+
+    async fn answer(&self, channel: &str, request: LlmRequest) -> Result<()> {
+        let prompt = self.render_prompt(channel, &request).await;
+        println!("{}{} Received: {}", "[AI]".orange(), "[RX]".green(), prompt);
+        let reply_to = request.envelope.clone();
+        let memories = self.memory.system_prompt(&request.sender, &request.prompt).await;
+        self.memory.remember(&request.sender, &request.prompt).await;
+
+        let persona = self.args.personas.get(channel).await;
+        let model = persona.model.clone().unwrap_or(self.config.model.clone());
+        let mut options = self.options.clone();
+        if let Some(temperature) = persona.temperature {
+            options = options.temperature(temperature);
+        }
+
+        // Memories are not kept in the history
+        let system_prompts = self.system_prompts(channel, &persona, memories).await?;
+        // Make room before sending, not after
+        self.compact(channel, &system_prompts, chat_history::estimate_tokens(&prompt)).await;
+        // Snapshot of the history, other replies can be generated at the same time
+        let messages = self.histories
+            .lock().await
+            .entry(channel.to_string())
+            .or_default()
+            .messages(&system_prompts);
+        let mut turns = vec![ChatMessage::user(prompt)];
+        let mut tool_calls = 0;
+
+        let answer = loop {
+            let mut messages = messages.clone();
+            messages.extend(turns.iter().cloned());
+            let chat_request = ChatMessageRequest::new(model.clone(), messages).options(
+                options.clone()
+            );
+            let response = self.ollama.send_chat_messages(chat_request).await?;
+            let answer = response.message.map(|message| message.content).unwrap_or_default();
+            turns.push(ChatMessage::assistant(answer.clone()));
+
+            let Some(call) = ToolCall::parse(&answer) else {
+                break answer;
+            };
+            if tool_calls == MAX_TOOL_CALLS {
+                println!("{}{} Too many tool calls, dropping reply", "[AI]".orange(), "[TOOL]".red());
+                break String::new();
+            }
+            tool_calls += 1;
+
+            let tool_name = call.tool.clone();
+            let result = match self.tools.call(self.args.clone(), &request.sender, call).await {
+                Ok(result) => result,
+                Err(err) => format!("error: {}", err),
+            };
+            println!("{}{} {}: {}", "[AI]".orange(), "[TOOL]".yellow(), tool_name, result);
+            turns.push(ChatMessage::user(format!("[tool:{}]: {}", tool_name, result)));
+        };
+
+        {
+            let mut histories = self.histories.lock().await;
+            let history = histories.entry(channel.to_string()).or_default();
+            for turn in turns {
+                history.push(turn);
+            }
+        }
+
+        if answer.is_empty() {
+            return Ok(());
+        }
+        println!("{}{} Generated: {}", "[AI]".orange(), "[ANSWER]".blue(), answer);
+
+        let filter_context = self.filter_context().await;
+        // The filters check the exact text sent to the chat, the markup could hide a command
+        let Some(text) = self.reply_filter.run(&ssml::strip_markup(&answer), &filter_context) else {
+            return Ok(());
+        };
+        let speech = self.reply_filter.run(&answer, &filter_context).unwrap_or(text.clone());
+        self.args.events.publish(BotEvent::LlmReply { bot_name: filter_context.bot_name, text, speech, reply_to });
+        Ok(())
+    }
+}
+
+pub async fn start(args: Arc<Args>) -> Result<()> {
+    let chat = args.events.subscribe("ollama", &[Topic::Chat, Topic::Alert]);
+    // Commands get their own queue, a chat burst cannot evict them
+    let requests = args.events.subscribe_with("ollama-requests", &[Topic::LlmRequest], 64, QueuePolicy::DropNewest);
+    let config = OllamaConfig::load_config::<OllamaConfig>(
+        OllamaConfig::default(),
+        "ollama_config.toml"
+    ).await?;
+
+    let memory_config = MemoryConfig::load_config::<MemoryConfig>(
+        MemoryConfig::default(),
+        "memory_config.toml"
+    ).await?;
+
+    let reply_filter_config = ReplyFilterConfig::load_config::<ReplyFilterConfig>(
+        ReplyFilterConfig::default(),
+        "reply_filter_config.toml"
+    ).await?;
+
+    let trigger_config = TriggerConfig::load_config::<TriggerConfig>(
+        TriggerConfig::default(),
+        "trigger_config.toml"
+    ).await?;
+    // One engine per persona, each with its own cooldowns, built now so bad rules are reported at startup
+    let mut trigger_engines: HashMap<String, TriggerEngine> = HashMap::new();
+    for persona in args.personas.all().await {
+        let config = persona.triggers.clone().unwrap_or(trigger_config.clone());
+        trigger_engines.insert(persona.name, TriggerEngine::new(config));
+    }
+
+    let message_template = PromptTemplate::load(
+        &config.message_prompt_file,
+        DEFAULT_MESSAGE_PROMPT
+    )?;
+
+    let mut scheduler = LlmScheduler::new(
+        Duration::from_secs(config.request_ttl),
+        config.merge_user_requests
     );
-    let generation_stream = ollama.generate(generation_request).await?;
-    let context = &generation_stream.context.unwrap();
+    let permits = Arc::new(Semaphore::new(config.max_concurrent_requests.max(1)));
+    let ollama = Ollama::new(config.host.clone(), config.port);
+    let worker = Arc::new(LlmWorker {
+        args: args.clone(),
+        memory: Memory::start(MemoryStore::load(memory_config)?, ollama.clone()),
+        ollama,
+        options: GenerationOptions::default().num_ctx(config.num_ctx),
+        config,
+        tools: ToolRegistry::with_builtin_tools(),
+        reply_filter: ReplyFilterPipeline::new(&reply_filter_config)?,
+        system_templates: Mutex::new(HashMap::new()),
+        message_template: Mutex::new(message_template),
+        histories: Mutex::new(HashMap::new()),
+    });
 
     loop {
-        let payload = args.ollama.recv().await;
-        println!("{}{} Received: {}", "[AI]".orange(), "[RX]".green(), payload);
-        let generation_request = GenerationRequest::new(model_name.into(), payload).context(
-            context.clone()
-        );
-
-        let generation_stream = ollama.generate(generation_request).await?;
-        println!(
-            "{}{} Generated: {}",
-            "[AI]".orange(),
-            "[ANSWER]".blue(),
-            generation_stream.response
-        );
-
-        args.twitch_queue.send(generation_stream.response).await;
-    }
-    //  loop {
-    //     tokio::time::sleep(tokio::time::Duration::from_secs(3)).await;
-    // }
+        let event = tokio::select! {
+            // Commands first, a chat burst must not delay them
+            biased;
+
+            event = requests.recv() => event,
+
+            event = chat.recv() => event,
+
+            Ok(permit) = permits.clone().acquire_owned(), if !scheduler.is_empty() => {
+                let Some(request) = scheduler.pop() else {
+                    continue;
+                };
+                let worker = worker.clone();
+                let channel = request.channel_or(&args.bot_info.get_main_channel().await);
+                tokio::spawn(async move {
+                    if let Err(err) = worker.answer(&channel, request).await {
+                        println!("{}{} Failed to answer: {}", "[AI]".orange(), "[ERROR]".red(), err);
+                    }
+                    drop(permit);
+                });
+                continue;
+            }
+        };
+
+        let request = match &*event {
+            BotEvent::LlmRequest(request) => request.clone(),
+            BotEvent::Chat(envelope) => {
+                let irc_message = &envelope.message;
+                let sender = &envelope.author.login;
+                let bot_name = args.bot_info.get_name().await;
+                let persona = args.personas.get(&envelope.channel).await;
+                let trigger_engine = trigger_engines
+                    .entry(persona.name.clone())
+                    .or_insert_with(|| TriggerEngine::new(persona.triggers.clone().unwrap_or(trigger_config.clone())));
+                let request = match trigger_engine.evaluate(&bot_name, irc_message) {
+                    Some(trigger) => {
+                        println!("[TRIGGER] {:?} from {}", trigger.reason, sender);
+                        LlmRequest::new(sender, trigger.text, LlmRequestKind::Reply)
+                    }
+                    None => LlmRequest::new(sender, irc_message.payload.clone(), LlmRequestKind::Context),
+                };
+                request
+                    .with_variables(templates::message_variables(irc_message))
+                    .with_envelope(envelope.clone())
+            }
+            BotEvent::Alert(envelope) => {
+                // Twitch escapes the spaces of the tag values
+                let notice = envelope.message.tag("system-msg").unwrap_or_default().replace("\\s", " ");
+                LlmRequest::new(&envelope.author.login, notice, LlmRequestKind::Context)
+                    .with_variables(templates::message_variables(&envelope.message))
+                    .with_envelope(envelope.clone())
+            }
+            _ => continue,
+        };
+        let channel = request.channel_or(&args.bot_info.get_main_channel().await);
+        match request.kind {
+            LlmRequestKind::Reply => scheduler.push(request),
+            LlmRequestKind::Context => {
+                worker.add_context(&channel, request).await;
+                // A summary takes a model round trip, the loop keeps serving meanwhile
+                let worker = worker.clone();
+                let channel = channel.clone();
+                tokio::spawn(async move { worker.compact_channel(&channel).await });
+            }
+            LlmRequestKind::ShowSummary => {
+                let summary = worker.histories
+                    .lock().await
+                    .get(&channel)
+                    .and_then(|history| history.summary().map(|summary| summary.to_string()))
+                    .unwrap_or("No summary yet".into());
+                if let Some(summary) = worker.chat_text(&summary).await {
+                    args.events.publish(BotEvent::SendChat { channel, text: summary });
+                }
+            }
+            LlmRequestKind::ResetSummary => {
+                if let Some(history) = worker.histories.lock().await.get_mut(&channel) {
+                    history.reset_summary();
+                }
+                println!("{}{} Summary of #{} reset", "[AI]".orange(), "[SUMMARY]".blue(), channel);
+            }
+            LlmRequestKind::ResetHistory => {
+                worker.histories.lock().await.remove(&channel);
+                println!("{}{} History of #{} reset", "[AI]".orange(), "[HISTORY]".blue(), channel);
+            }
+            LlmRequestKind::QueuePosition => {
+                let answer = match scheduler.position(&request.sender) {
+                    Some(position) => format!("@{} your question is #{} in the queue", request.sender, position),
+                    None => format!("@{} you have no question waiting in the queue", request.sender),
+                };
+                args.events.publish(BotEvent::SendChat { channel, text: answer });
+            }
+        }
+    }
 }
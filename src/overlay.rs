@@ -0,0 +1,186 @@
+// Browser source overlay: serves the overlay page and pushes the TTS clips to it over a websocket
+#![allow(dead_code)]
+
+use std::time::Duration;
+
+use anyhow::{ anyhow, Result };
+use base64::{ engine::general_purpose::STANDARD as BASE64, Engine };
+use futures::{ SinkExt, StreamExt };
+use serde::Deserialize;
+use serde_json::json;
+use tokio::{
+    io::{ AsyncReadExt, AsyncWriteExt },
+    net::{ TcpListener, TcpStream },
+    sync::{ broadcast, mpsc, Mutex },
+};
+use tokio_tungstenite::{ tungstenite::Message, WebSocketStream };
+
+use crate::audio_sink::AudioClip;
+use crate::colors::Colorize;
+
+const OVERLAY_PAGE: &str = include_str!("overlay.html");
+
+/// Extra time given to the overlay to report the end of a clip
+const PLAYBACK_MARGIN: Duration = Duration::from_secs(5);
+
+/// Time given to a client to send its request, the connection is closed after it
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum OverlayEvent {
+    /// The clip with this id has been played
+    Ended {
+        id: u64,
+    },
+}
+
+#[derive(Debug)]
+pub struct OverlaySink {
+    clips: broadcast::Sender<String>,
+    completed: Mutex<mpsc::UnboundedReceiver<u64>>,
+}
+
+impl OverlaySink {
+    /// Starts the overlay HTTP and websocket server on `address`
+    pub async fn bind(address: &str) -> Result<Self> {
+        let listener = TcpListener::bind(address).await?;
+        println!("{} Overlay available on http://{}/", "[TTS][OVERLAY]".cyan(), address);
+        let (clips, _) = broadcast::channel::<String>(16);
+        let (completed_sender, completed) = mpsc::unbounded_channel::<u64>();
+
+        let sender = clips.clone();
+        tokio::spawn(async move {
+            loop {
+                let Ok((stream, peer)) = listener.accept().await else {
+                    continue;
+                };
+                let clips = sender.subscribe();
+                let completed = completed_sender.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = handle_connection(stream, clips, completed).await {
+                        println!("{} {}: {}", "[TTS][OVERLAY]".red(), peer, err);
+                    }
+                });
+            }
+        });
+
+        Ok(OverlaySink { clips, completed: Mutex::new(completed) })
+    }
+
+    /// Sends the clip to the overlay pages and waits until one of them has played it
+    pub async fn play(&self, clip: &AudioClip) -> Result<()> {
+        if self.clips.receiver_count() == 0 {
+            return Ok(());
+        }
+
+        let mut completed = self.completed.lock().await;
+        // Reports of clips that already timed out
+        while completed.try_recv().is_ok() {}
+
+        let message =
+            json!({
+            "type": "clip",
+            "id": clip.sequence,
+            "mime": clip.mime_type(),
+            "audio": BASE64.encode(&clip.bytes),
+            "caption": clip.text,
+            "speaker": clip.speaker,
+            "color": clip.color,
+        });
+        _ = self.clips.send(message.to_string());
+
+        let wait_completion = async {
+            while let Some(id) = completed.recv().await {
+                if id >= clip.sequence {
+                    break;
+                }
+            }
+        };
+        if tokio::time::timeout(clip.duration + PLAYBACK_MARGIN, wait_completion).await.is_err() {
+            println!("{} Clip {} not reported as played", "[TTS][OVERLAY]".yellow(), clip.sequence);
+        }
+        Ok(())
+    }
+
+    /// Tells the overlay pages to stop the clip they are playing
+    pub fn stop(&self) {
+        _ = self.clips.send(json!({ "type": "skip" }).to_string());
+    }
+}
+
+/// Serves the overlay page, or upgrades the connection to the websocket the clips are pushed on
+async fn handshake(mut stream: TcpStream) -> Result<Option<WebSocketStream<TcpStream>>> {
+    // Look at the request headers without consuming them, the websocket handshake needs them
+    let mut buffer = [0u8; 4096];
+    let request = loop {
+        let read = stream.peek(&mut buffer).await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        let request = String::from_utf8_lossy(&buffer[..read]).to_string();
+        if request.contains("\r\n\r\n") || read == buffer.len() {
+            break request;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    };
+
+    if !request.to_lowercase().contains("upgrade: websocket") {
+        _ = stream.read(&mut buffer).await?;
+        let response = match request.split_whitespace().nth(1) {
+            Some("/") | Some("/index.html") =>
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    OVERLAY_PAGE.len(),
+                    OVERLAY_PAGE
+                ),
+            _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+        };
+        stream.write_all(response.as_bytes()).await?;
+        return Ok(None);
+    }
+
+    Ok(Some(tokio_tungstenite::accept_async(stream).await?))
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    mut clips: broadcast::Receiver<String>,
+    completed: mpsc::UnboundedSender<u64>
+) -> Result<()> {
+    // Dropping the stream closes the connections that never finish their request
+    let websocket = match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake(stream)).await {
+        Ok(websocket) => websocket?,
+        Err(_) => return Err(anyhow!("no request within {:?}, closing the connection", HANDSHAKE_TIMEOUT)),
+    };
+    let Some(websocket) = websocket else {
+        return Ok(());
+    };
+    println!("{} Overlay connected", "[TTS][OVERLAY]".cyan());
+    let (mut write, mut read) = websocket.split();
+
+    loop {
+        tokio::select! {
+            clip = clips.recv() => {
+                match clip {
+                    Ok(clip) => write.send(Message::text(clip)).await?,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+
+            message = read.next() => {
+                let Some(Ok(message)) = message else {
+                    break;
+                };
+                if let Ok(text) = message.to_text() {
+                    if let Ok(OverlayEvent::Ended { id }) = serde_json::from_str(text) {
+                        _ = completed.send(id);
+                    }
+                }
+            }
+        }
+    }
+    println!("{} Overlay disconnected", "[TTS][OVERLAY]".cyan());
+    Ok(())
+}
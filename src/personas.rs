@@ -0,0 +1,117 @@
+// Named bot personalities, one active per channel
+#![allow(dead_code)]
+
+use std::{ collections::HashMap, sync::Arc };
+
+use anyhow::{ anyhow, Result };
+use serde::{ Deserialize, Serialize };
+use tokio::sync::RwLock;
+
+use crate::config_manager::ConfigManager;
+use crate::trigger::TriggerConfig;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Persona {
+    pub name: String,
+    /// Template of the system prompt, created with the default prompt if missing
+    pub system_prompt_file: String,
+    /// Model used instead of `OllamaConfig::model`
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    /// Locale of the TTS voice, e.g. it-IT
+    pub tts_locale: Option<String>,
+    /// Male or Female
+    pub tts_gender: Option<String>,
+    /// Trigger rules used instead of the ones in trigger_config.toml
+    pub triggers: Option<TriggerConfig>,
+}
+
+impl Default for Persona {
+    fn default() -> Self {
+        Persona {
+            name: "default".into(),
+            system_prompt_file: "system_prompt.txt".into(),
+            model: None,
+            temperature: None,
+            tts_locale: None,
+            tts_gender: None,
+            triggers: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PersonaConfig {
+    /// Forget the conversation when a moderator switches persona
+    pub reset_history_on_switch: bool,
+    /// Persona used in each channel until a moderator switches it, the first one otherwise
+    pub channel_defaults: HashMap<String, String>,
+    pub personas: Vec<Persona>,
+}
+
+impl ConfigManager for PersonaConfig {}
+
+impl Default for PersonaConfig {
+    fn default() -> Self {
+        PersonaConfig {
+            reset_history_on_switch: false,
+            channel_defaults: HashMap::new(),
+            personas: vec![Persona::default()],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Personas {
+    config: Arc<RwLock<PersonaConfig>>,
+    active: Arc<RwLock<HashMap<String, String>>>,
+}
+
+impl Personas {
+    pub fn new(config: PersonaConfig) -> Self {
+        Personas {
+            config: Arc::new(RwLock::new(config)),
+            active: Arc::default(),
+        }
+    }
+
+    /// Active persona of the channel
+    pub async fn get(&self, channel: &str) -> Persona {
+        let config = self.config.read().await;
+        let name = match self.active.read().await.get(channel) {
+            Some(name) => Some(name.clone()),
+            None => config.channel_defaults.get(channel).cloned(),
+        };
+        name.and_then(|name| config.personas.iter().find(|persona| persona.name == name))
+            .or(config.personas.first())
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub async fn switch(&self, channel: &str, name: &str) -> Result<Persona> {
+        let persona = self.config
+            .read().await
+            .personas.iter()
+            .find(|persona| persona.name.eq_ignore_ascii_case(name))
+            .cloned()
+            .ok_or_else(|| anyhow!("unknown persona {}", name))?;
+        self.active.write().await.insert(channel.to_string(), persona.name.clone());
+        Ok(persona)
+    }
+
+    pub async fn all(&self) -> Vec<Persona> {
+        self.config.read().await.personas.clone()
+    }
+
+    pub async fn names(&self) -> Vec<String> {
+        self.config
+            .read().await
+            .personas.iter()
+            .map(|persona| persona.name.clone())
+            .collect()
+    }
+
+    pub async fn reset_history_on_switch(&self) -> bool {
+        self.config.read().await.reset_history_on_switch
+    }
+}
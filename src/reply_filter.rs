@@ -0,0 +1,297 @@
+// Post generation filters applied to the LLM replies before they are sent to the chat
+#![allow(dead_code)]
+
+use std::collections::HashSet;
+
+use anyhow::Result;
+use regex::Regex;
+use serde::{ Deserialize, Serialize };
+
+use crate::colors::Colorize;
+use crate::config_manager::ConfigManager;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReplyFilterConfig {
+    /// Replies containing any of these words are never sent (case insensitive)
+    pub blocklist: Vec<String>,
+    pub strip_urls: bool,
+    /// Remove leading '/' and '.' so replies can not run chat commands like /ban
+    pub neutralize_commands: bool,
+    /// Remove the '@' of mentions to users that are not taking part in the chat
+    pub strip_unknown_mentions: bool,
+    /// Maximum reply length in characters, twitch drops messages above 500
+    pub max_length: usize,
+}
+
+impl ConfigManager for ReplyFilterConfig {}
+
+impl Default for ReplyFilterConfig {
+    fn default() -> Self {
+        ReplyFilterConfig {
+            blocklist: vec![],
+            strip_urls: true,
+            neutralize_commands: true,
+            strip_unknown_mentions: true,
+            max_length: 450,
+        }
+    }
+}
+
+/// What the reply is checked against
+#[derive(Debug, Clone, Default)]
+pub struct FilterContext {
+    pub bot_name: String,
+    /// Lowercase nicknames of the users seen in the chat
+    pub participants: HashSet<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterOutcome {
+    Pass,
+    Rewrite {
+        reply: String,
+        reason: String,
+    },
+    Veto {
+        reason: String,
+    },
+}
+
+pub trait ReplyFilter: Send + Sync {
+    fn name(&self) -> &str;
+    fn apply(&self, reply: &str, context: &FilterContext) -> FilterOutcome;
+}
+
+struct Blocklist {
+    words: Vec<String>,
+}
+
+impl ReplyFilter for Blocklist {
+    fn name(&self) -> &str {
+        "blocklist"
+    }
+
+    fn apply(&self, reply: &str, _context: &FilterContext) -> FilterOutcome {
+        let reply = reply.to_lowercase();
+        match self.words.iter().find(|word| reply.contains(word.as_str())) {
+            Some(word) => FilterOutcome::Veto { reason: format!("blocked word \"{}\"", word) },
+            None => FilterOutcome::Pass,
+        }
+    }
+}
+
+struct UrlStripper {
+    url: Regex,
+}
+
+impl ReplyFilter for UrlStripper {
+    fn name(&self) -> &str {
+        "urls"
+    }
+
+    fn apply(&self, reply: &str, _context: &FilterContext) -> FilterOutcome {
+        if !self.url.is_match(reply) {
+            return FilterOutcome::Pass;
+        }
+        FilterOutcome::Rewrite {
+            reply: self.url.replace_all(reply, "").split_whitespace().collect::<Vec<_>>().join(" "),
+            reason: "links removed".into(),
+        }
+    }
+}
+
+/// Line breaks would send a second IRC line, other control characters garble the chat
+struct ControlCharacters;
+
+impl ReplyFilter for ControlCharacters {
+    fn name(&self) -> &str {
+        "control"
+    }
+
+    fn apply(&self, reply: &str, _context: &FilterContext) -> FilterOutcome {
+        if !reply.chars().any(|c| c.is_control()) {
+            return FilterOutcome::Pass;
+        }
+        FilterOutcome::Rewrite {
+            reply: reply
+                .split(|c: char| c.is_control())
+                .filter(|part| !part.trim().is_empty())
+                .collect::<Vec<_>>()
+                .join(" "),
+            reason: "control characters removed".into(),
+        }
+    }
+}
+
+struct CommandNeutralizer;
+
+impl ReplyFilter for CommandNeutralizer {
+    fn name(&self) -> &str {
+        "commands"
+    }
+
+    fn apply(&self, reply: &str, _context: &FilterContext) -> FilterOutcome {
+        let neutralized = reply.trim_start().trim_start_matches(['/', '.', '\\', ' ']);
+        if neutralized.len() == reply.trim_start().len() {
+            return FilterOutcome::Pass;
+        }
+        FilterOutcome::Rewrite {
+            reply: neutralized.to_string(),
+            reason: "leading chat command neutralized".into(),
+        }
+    }
+}
+
+struct MentionFilter {
+    mention: Regex,
+}
+
+impl ReplyFilter for MentionFilter {
+    fn name(&self) -> &str {
+        "mentions"
+    }
+
+    fn apply(&self, reply: &str, context: &FilterContext) -> FilterOutcome {
+        let mut removed = Vec::new();
+        let rewritten = self.mention.replace_all(reply, |captures: &regex::Captures| {
+            let name = &captures[1];
+            let lowercase_name = name.to_lowercase();
+            if
+                lowercase_name == context.bot_name.to_lowercase() ||
+                context.participants.contains(&lowercase_name)
+            {
+                captures[0].to_string()
+            } else {
+                removed.push(name.to_string());
+                name.to_string()
+            }
+        });
+        if removed.is_empty() {
+            return FilterOutcome::Pass;
+        }
+        FilterOutcome::Rewrite {
+            reply: rewritten.to_string(),
+            reason: format!("mentions of non participants removed: {}", removed.join(", ")),
+        }
+    }
+}
+
+struct LengthCap {
+    max_length: usize,
+}
+
+impl ReplyFilter for LengthCap {
+    fn name(&self) -> &str {
+        "length"
+    }
+
+    fn apply(&self, reply: &str, _context: &FilterContext) -> FilterOutcome {
+        if reply.chars().count() <= self.max_length {
+            return FilterOutcome::Pass;
+        }
+        let truncated = reply.chars().take(self.max_length.saturating_sub(1)).collect::<String>();
+        // Cut on the last full word when there is one
+        let truncated = match truncated.rfind(' ') {
+            Some(index) if index > 0 => &truncated[..index],
+            _ => truncated.as_str(),
+        };
+        FilterOutcome::Rewrite {
+            reply: format!("{}…", truncated.trim_end()),
+            reason: format!("truncated to {} characters", self.max_length),
+        }
+    }
+}
+
+pub struct ReplyFilterPipeline {
+    stages: Vec<Box<dyn ReplyFilter>>,
+}
+
+impl ReplyFilterPipeline {
+    pub fn new(config: &ReplyFilterConfig) -> Result<Self> {
+        // Always first, the other stages see a single line
+        let mut stages: Vec<Box<dyn ReplyFilter>> = vec![Box::new(ControlCharacters)];
+        if !config.blocklist.is_empty() {
+            stages.push(
+                Box::new(Blocklist {
+                    words: config.blocklist
+                        .iter()
+                        .map(|word| word.to_lowercase())
+                        .collect(),
+                })
+            );
+        }
+        if config.strip_urls {
+            stages.push(
+                Box::new(UrlStripper {
+                    url: Regex::new(
+                        r"(?i)\b(?:https?://|www\.)\S+|\b[a-z0-9-]+\.(?:com|net|org|tv|gg|io|ly|me|co|xyz)\b(?:/\S*)?"
+                    )?,
+                })
+            );
+        }
+        if config.neutralize_commands {
+            stages.push(Box::new(CommandNeutralizer));
+        }
+        if config.strip_unknown_mentions {
+            stages.push(Box::new(MentionFilter { mention: Regex::new(r"@(\w+)")? }));
+        }
+        stages.push(Box::new(LengthCap { max_length: config.max_length }));
+        Ok(ReplyFilterPipeline { stages })
+    }
+
+    pub fn push(&mut self, stage: Box<dyn ReplyFilter>) {
+        self.stages.push(stage);
+    }
+
+    /// Runs all the stages in order, None if the reply was vetoed or nothing is left of it.
+    /// `reply` must be the exact text sent to the chat, e.g. without the speech markup.
+    pub fn run(&self, reply: &str, context: &FilterContext) -> Option<String> {
+        let mut reply = reply.trim().to_string();
+        for stage in &self.stages {
+            match stage.apply(&reply, context) {
+                FilterOutcome::Pass => {}
+                FilterOutcome::Rewrite { reply: rewritten, reason } => {
+                    println!("{}[{}] {}", "[FILTER]".yellow(), stage.name(), reason);
+                    reply = rewritten.trim().to_string();
+                }
+                FilterOutcome::Veto { reason } => {
+                    println!("{}[{}] Reply vetoed: {}", "[FILTER]".red(), stage.name(), reason);
+                    return None;
+                }
+            }
+        }
+        if reply.is_empty() {
+            println!("{} Nothing left of the reply", "[FILTER]".red());
+            return None;
+        }
+        Some(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssml;
+
+    fn run(reply: &str) -> Option<String> {
+        let pipeline = ReplyFilterPipeline::new(&ReplyFilterConfig::default()).unwrap();
+        pipeline.run(reply, &FilterContext { bot_name: "botox".into(), ..FilterContext::default() })
+    }
+
+    #[test]
+    fn commands_hidden_behind_markup_are_neutralized() {
+        assert_eq!(run(&ssml::strip_markup("[pause]/ban victim")), Some("ban victim".into()));
+    }
+
+    #[test]
+    fn line_breaks_can_not_start_a_new_irc_line() {
+        let reply = run("hello\r\nPRIVMSG #other :spam").unwrap();
+        assert!(!reply.contains(['\r', '\n']));
+        assert_eq!(reply, "hello PRIVMSG #other :spam");
+    }
+
+    #[test]
+    fn command_after_a_line_break_is_neutralized() {
+        assert_eq!(run("\n/ban victim"), Some("ban victim".into()));
+    }
+}
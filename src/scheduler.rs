@@ -0,0 +1,201 @@
+// Fair scheduling of the LLM requests: round robin across users, stale requests dropped
+#![allow(dead_code)]
+
+use std::collections::VecDeque;
+use std::time::{ Duration, Instant };
+
+use crate::colors::Colorize;
+use crate::ollama::LlmRequest;
+
+#[derive(Debug)]
+struct PendingRequest {
+    request: LlmRequest,
+    queued_at: Instant,
+}
+
+impl PendingRequest {
+    /// Priority of the chat message asking, e.g. a cheer, 0 if the bot asked itself
+    fn priority(&self) -> u32 {
+        self.request.envelope
+            .as_ref()
+            .map(|envelope| envelope.priority)
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug)]
+pub struct LlmScheduler {
+    /// One queue per user, the front user is served next
+    users: VecDeque<(String, VecDeque<PendingRequest>)>,
+    ttl: Duration,
+    merge_user_requests: bool,
+}
+
+impl LlmScheduler {
+    pub fn new(ttl: Duration, merge_user_requests: bool) -> Self {
+        LlmScheduler {
+            users: VecDeque::new(),
+            ttl,
+            merge_user_requests,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.users
+            .iter()
+            .map(|(_, requests)| requests.len())
+            .sum()
+    }
+
+    pub fn push(&mut self, request: LlmRequest) {
+        self.push_at(request, Instant::now());
+    }
+
+    fn push_at(&mut self, request: LlmRequest, now: Instant) {
+        let user = request.sender.to_lowercase();
+        let pending = PendingRequest { request, queued_at: now };
+
+        match self.users.iter_mut().find(|(name, _)| *name == user) {
+            Some((_, requests)) => {
+                match requests.back_mut() {
+                    // Answer all the pending questions of the user at once
+                    Some(last) if self.merge_user_requests => {
+                        last.request.prompt.push('\n');
+                        last.request.prompt.push_str(&pending.request.prompt);
+                        // The answer replies to the latest question
+                        if pending.request.envelope.is_some() {
+                            last.request.envelope = pending.request.envelope;
+                        }
+                        // and waits no longer than the TTL from it
+                        last.queued_at = pending.queued_at;
+                    }
+                    _ => requests.push_back(pending),
+                }
+            }
+            None => self.users.push_back((user, VecDeque::from([pending]))),
+        }
+    }
+
+    fn is_expired(&self, pending: &PendingRequest, now: Instant) -> bool {
+        now.saturating_duration_since(pending.queued_at) > self.ttl
+    }
+
+    /// Index of the next user to serve: the one whose next request has the highest priority,
+    /// the first in the rotation among equals
+    fn next_user(&self) -> Option<usize> {
+        let mut next: Option<(usize, u32)> = None;
+        for (index, (_, requests)) in self.users.iter().enumerate() {
+            let priority = requests.front().map(|pending| pending.priority()).unwrap_or_default();
+            if next.is_none_or(|(_, highest)| priority > highest) {
+                next = Some((index, priority));
+            }
+        }
+        next.map(|(index, _)| index)
+    }
+
+    /// Next request to serve, skipping the ones waiting for longer than the TTL
+    pub fn pop(&mut self) -> Option<LlmRequest> {
+        self.pop_at(Instant::now())
+    }
+
+    fn pop_at(&mut self, now: Instant) -> Option<LlmRequest> {
+        while let Some((user, mut requests)) = self.next_user().and_then(|index| self.users.remove(index)) {
+            let Some(pending) = requests.pop_front() else {
+                continue;
+            };
+            if !requests.is_empty() {
+                self.users.push_back((user, requests));
+            }
+
+            if self.is_expired(&pending, now) {
+                println!(
+                    "{}{} Dropping stale request: {}",
+                    "[AI]".orange(),
+                    "[QUEUE]".red(),
+                    pending.request.prompt
+                );
+                continue;
+            }
+            return Some(pending.request);
+        }
+        None
+    }
+
+    /// 1-based position of the next request of `user`, following the priority and round robin order.
+    /// Stale requests are not counted, they will be dropped before being served.
+    pub fn position(&self, user: &str) -> Option<usize> {
+        self.position_at(user, Instant::now())
+    }
+
+    fn position_at(&self, user: &str, now: Instant) -> Option<usize> {
+        let user = user.to_lowercase();
+        let waiting = self.users
+            .iter()
+            .filter(|(_, requests)| !requests.iter().all(|pending| self.is_expired(pending, now)))
+            .map(|(name, requests)| (name, requests.front().map(|pending| pending.priority()).unwrap_or_default()))
+            .collect::<Vec<_>>();
+        let index = waiting.iter().position(|(name, _)| **name == user)?;
+        let priority = waiting[index].1;
+        // Users with a higher priority, then the ones ahead in the rotation, are served once before this one
+        let ahead = waiting
+            .iter()
+            .enumerate()
+            .filter(|(other, (_, other_priority))| *other_priority > priority || (*other_priority == priority && *other < index))
+            .count();
+        Some(ahead + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::chat_envelope::ChatEnvelope;
+    use crate::irc_parser::{ Context, IrcMessage };
+    use crate::ollama::LlmRequestKind;
+
+    fn request(sender: &str, prompt: &str) -> LlmRequest {
+        LlmRequest::new(sender, prompt, LlmRequestKind::Reply)
+    }
+
+    #[test]
+    fn merged_question_waits_from_its_own_arrival() {
+        let mut scheduler = LlmScheduler::new(Duration::from_secs(50), true);
+        let start = Instant::now();
+        scheduler.push_at(request("alice", "first"), start);
+        scheduler.push_at(request("alice", "second"), start + Duration::from_secs(40));
+
+        let merged = scheduler.pop_at(start + Duration::from_secs(60)).expect("the newest question is not stale yet");
+        assert_eq!(merged.prompt, "first\nsecond");
+    }
+
+    #[test]
+    fn higher_priority_is_served_first() {
+        let mut scheduler = LlmScheduler::new(Duration::from_secs(60), false);
+        let cheer = IrcMessage::new(HashMap::new(), Context::new("bob", "PRIVMSG", "#channel"), "cheer100 hi");
+        scheduler.push(request("alice", "first"));
+        scheduler.push(request("bob", "cheered").with_envelope(ChatEnvelope::new(cheer).with_priority(100)));
+
+        assert_eq!(scheduler.position("bob"), Some(1));
+        assert_eq!(scheduler.position("alice"), Some(2));
+        assert_eq!(scheduler.pop().map(|request| request.prompt), Some("cheered".into()));
+        assert_eq!(scheduler.pop().map(|request| request.prompt), Some("first".into()));
+    }
+
+    #[test]
+    fn position_skips_stale_requests() {
+        let mut scheduler = LlmScheduler::new(Duration::from_secs(20), false);
+        let start = Instant::now();
+        scheduler.push_at(request("alice", "stale"), start);
+        scheduler.push_at(request("bob", "fresh"), start + Duration::from_secs(30));
+
+        let now = start + Duration::from_secs(30);
+        assert_eq!(scheduler.position_at("alice", now), None);
+        assert_eq!(scheduler.position_at("bob", now), Some(1));
+    }
+}
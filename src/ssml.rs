@@ -0,0 +1,187 @@
+// SSML for the TTS: prosody settings, escaping of the chat text and inline markup of the bot replies
+#![allow(dead_code)]
+
+use serde::{ Deserialize, Serialize };
+
+/// Inline markup the bot can use in its replies, given to the model as a system prompt
+pub const MARKUP_PROMPT: &str =
+    "Your replies are also read aloud. You can use this markup, it is removed from the chat: \
+    *word* to stress a word, [pause] or [pause 800ms] for a pause, [whisper]text[/whisper] to whisper.";
+
+/// Longest pause the markup can ask for
+const MAX_PAUSE_MS: u32 = 5000;
+const DEFAULT_PAUSE_MS: u32 = 500;
+
+/// Rate, pitch and volume of the speech, unset values are inherited from the less specific level
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Prosody {
+    /// Speed change in percent, e.g. -20 or 30
+    pub rate: Option<i32>,
+    /// Pitch change in Hz, e.g. -10 or 15
+    pub pitch: Option<i32>,
+    /// Volume change in percent, e.g. -50 or 20
+    pub volume: Option<i32>,
+}
+
+impl Prosody {
+    /// `self` with the values set in `other` replacing its own
+    pub fn merge(&self, other: &Prosody) -> Prosody {
+        Prosody {
+            rate: other.rate.or(self.rate),
+            pitch: other.pitch.or(self.pitch),
+            volume: other.volume.or(self.volume),
+        }
+    }
+
+    pub fn rate(&self) -> i32 {
+        self.rate.unwrap_or_default().clamp(-100, 200)
+    }
+
+    pub fn pitch(&self) -> i32 {
+        self.pitch.unwrap_or_default().clamp(-100, 100)
+    }
+
+    pub fn volume(&self) -> i32 {
+        self.volume.unwrap_or_default().clamp(-100, 100)
+    }
+}
+
+/// Escapes `text` so it can be put inside an SSML element
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            // Control characters are not allowed in XML
+            c if c.is_control() && c != '\n' && c != '\t' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Markup {
+    Text(String),
+    Emphasis(String),
+    Pause(u32),
+    WhisperStart,
+    WhisperEnd,
+}
+
+/// Milliseconds of a "[pause]", "[pause 800ms]" or "[pause 1s]" tag, None if it is not a pause
+fn parse_pause(tag: &str) -> Option<u32> {
+    let mut parts = tag.split_whitespace();
+    if parts.next()? != "pause" {
+        return None;
+    }
+    let milliseconds = match parts.next() {
+        None => DEFAULT_PAUSE_MS,
+        Some(length) =>
+            match length.strip_suffix("ms") {
+                Some(milliseconds) => milliseconds.parse().ok()?,
+                None => length.strip_suffix('s')?.parse::<u32>().ok()?.saturating_mul(1000),
+            }
+    };
+    Some(milliseconds.min(MAX_PAUSE_MS))
+}
+
+fn parse_markup(text: &str) -> Vec<Markup> {
+    let mut parts = Vec::new();
+    let mut plain = String::new();
+    let mut rest = text;
+
+    while let Some(c) = rest.chars().next() {
+        let markup = match c {
+            '[' =>
+                rest.find(']').and_then(|end| {
+                    let tag = rest[1..end].trim().to_lowercase();
+                    let markup = match tag.as_str() {
+                        "whisper" => Some(Markup::WhisperStart),
+                        "/whisper" => Some(Markup::WhisperEnd),
+                        tag => parse_pause(tag).map(Markup::Pause),
+                    };
+                    markup.map(|markup| (markup, end + 1))
+                }),
+            '*' =>
+                rest[1..].find('*').and_then(|end| {
+                    let word = &rest[1..end + 1];
+                    // "2 * 3 * 4" is not an emphasis
+                    let valid = !word.is_empty() && !word.starts_with(' ') && !word.ends_with(' ');
+                    valid.then(|| (Markup::Emphasis(word.to_string()), end + 2))
+                }),
+            _ => None,
+        };
+
+        match markup {
+            Some((markup, length)) => {
+                if !plain.is_empty() {
+                    parts.push(Markup::Text(std::mem::take(&mut plain)));
+                }
+                parts.push(markup);
+                rest = &rest[length..];
+            }
+            None => {
+                plain.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    if !plain.is_empty() {
+        parts.push(Markup::Text(plain));
+    }
+    parts
+}
+
+/// SSML body of a chat message, every character of the chatter is read as it is
+pub fn from_text(text: &str) -> String {
+    escape(text)
+}
+
+/// SSML body of a bot reply, turning the inline markup into SSML elements.
+/// Emphasis and whisper are approximated with prosody, so they work with every voice: msedge-tts
+/// wraps the body in its own prosody element and does not declare the mstts namespace,
+/// so the speaking styles of mstts:express-as can not be used.
+pub fn from_markup(text: &str) -> String {
+    let mut ssml = String::new();
+    let mut whispering = false;
+    for part in parse_markup(text) {
+        match part {
+            Markup::Text(text) => ssml.push_str(&escape(&text)),
+            Markup::Emphasis(text) =>
+                ssml.push_str(&format!("<prosody pitch='+10%' volume='+20%'>{}</prosody>", escape(&text))),
+            Markup::Pause(milliseconds) => ssml.push_str(&format!("<break time='{}ms'/>", milliseconds)),
+            Markup::WhisperStart if !whispering => {
+                whispering = true;
+                ssml.push_str("<prosody volume='x-soft' rate='-10%' pitch='-5%'>");
+            }
+            Markup::WhisperEnd if whispering => {
+                whispering = false;
+                ssml.push_str("</prosody>");
+            }
+            Markup::WhisperStart | Markup::WhisperEnd => {}
+        }
+    }
+    if whispering {
+        ssml.push_str("</prosody>");
+    }
+    ssml
+}
+
+/// `text` without the inline markup, as it is shown in the chat
+pub fn strip_markup(text: &str) -> String {
+    let stripped = parse_markup(text)
+        .into_iter()
+        .filter_map(|part| {
+            match part {
+                Markup::Text(text) | Markup::Emphasis(text) => Some(text),
+                _ => None,
+            }
+        })
+        .collect::<String>();
+    stripped.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
@@ -0,0 +1,138 @@
+// Prompt templates with {{variable}} placeholders, reloaded when their file changes
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use anyhow::Result;
+use chrono::{ DateTime, Local };
+
+use crate::colors::Colorize;
+use crate::irc_parser::IrcMessage;
+
+pub type TemplateVariables = HashMap<String, String>;
+
+/// Replaces every {{name}} with its value, unknown variables are replaced with nothing
+pub fn render(template: &str, variables: &TemplateVariables) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find("}}") else {
+            rest = &rest[start..];
+            break;
+        };
+        let name = rest[start + 2..start + end].trim();
+        if let Some(value) = variables.get(name) {
+            rendered.push_str(value);
+        }
+        rest = &rest[start + end + 2..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+/// Variables describing a chat message and its author
+pub fn message_variables(message: &IrcMessage) -> TemplateVariables {
+    let sub_months = message
+        .tag("badge-info")
+        .unwrap_or_default()
+        .split(',')
+        .find_map(|badge| badge.strip_prefix("subscriber/"))
+        .unwrap_or("0");
+
+    TemplateVariables::from([
+        ("user".into(), message.context.sender.clone()),
+        (
+            "display_name".into(),
+            message.tag("display-name").unwrap_or(&message.context.sender).to_string(),
+        ),
+        ("badges".into(), message.tag("badges").unwrap_or_default().to_string()),
+        ("sub_months".into(), sub_months.to_string()),
+        ("first_message".into(), (message.tag("first-msg") == Some("1")).to_string()),
+    ])
+}
+
+fn format_uptime(started_at: DateTime<Local>) -> String {
+    let uptime = Local::now() - started_at;
+    format!("{}h {}m", uptime.num_hours(), uptime.num_minutes() % 60)
+}
+
+/// Variables shared by all the prompts
+pub fn global_variables(
+    bot_name: &str,
+    channel: &str,
+    started_at: DateTime<Local>,
+    stream_started_at: Option<DateTime<Local>>,
+    recent_chat: &[String]
+) -> TemplateVariables {
+    TemplateVariables::from([
+        ("bot_name".into(), bot_name.to_string()),
+        ("channel".into(), channel.to_string()),
+        ("time".into(), Local::now().format("%Y-%m-%d %H:%M").to_string()),
+        // Empty while the stream is offline
+        ("stream_uptime".into(), stream_started_at.map(format_uptime).unwrap_or_default()),
+        ("bot_uptime".into(), format_uptime(started_at)),
+        ("recent_chat".into(), recent_chat.join("\n")),
+    ])
+}
+
+#[derive(Debug, Clone)]
+pub struct PromptTemplate {
+    path: String,
+    content: String,
+    modified: Option<SystemTime>,
+}
+
+impl PromptTemplate {
+    /// Loads the template file, writing `default` to it if it does not exist yet
+    pub fn load(path: &str, default: &str) -> Result<Self> {
+        if std::fs::metadata(path).is_err() {
+            println!("No {path} template found. Saving default template to {path}");
+            std::fs::write(path, default)?;
+        }
+        let mut template = PromptTemplate {
+            path: path.to_string(),
+            content: default.to_string(),
+            modified: None,
+        };
+        template.reload_if_changed();
+        Ok(template)
+    }
+
+    fn reload_if_changed(&mut self) {
+        let modified = std::fs::metadata(&self.path).and_then(|metadata| metadata.modified()).ok();
+        if modified.is_none() || modified == self.modified {
+            return;
+        }
+        match std::fs::read_to_string(&self.path) {
+            Ok(content) => {
+                println!("{} Loaded {}", "[TEMPLATE]".cyan(), self.path);
+                self.content = content;
+                self.modified = modified;
+            }
+            Err(err) => println!("{} Failed to read {}: {}", "[TEMPLATE]".red(), self.path, err),
+        }
+    }
+
+    pub fn render(&mut self, variables: &TemplateVariables) -> String {
+        self.reload_if_changed();
+        render(&self.content, variables)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stream_uptime_is_empty_while_offline() {
+        let started_at = Local::now() - chrono::TimeDelta::minutes(90);
+        let offline = global_variables("botox", "channel", started_at, None, &[]);
+        assert_eq!(offline["stream_uptime"], "");
+        assert_eq!(offline["bot_uptime"], "1h 30m");
+
+        let live = global_variables("botox", "channel", Local::now(), Some(started_at), &[]);
+        assert_eq!(live["stream_uptime"], "1h 30m");
+    }
+}
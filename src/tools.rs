@@ -0,0 +1,392 @@
+// Actions the LLM can take, described with a JSON schema and checked against the requester permission
+#![allow(dead_code)]
+
+use std::sync::Arc;
+
+use anyhow::{ anyhow, Result };
+use chrono::Local;
+use futures::{ future::BoxFuture, FutureExt };
+use serde::{ Deserialize, Serialize };
+use regex::Regex;
+use serde_json::{ json, Value };
+use tokio::io::AsyncWriteExt;
+
+use crate::chat_state::Permission;
+use crate::event_bus::BotEvent;
+use crate::tts::TtsControl;
+use crate::Args;
+
+const QUOTES_FILE: &str = "quotes.txt";
+
+pub type ToolHandler = fn(Arc<Args>, Value) -> BoxFuture<'static, Result<String>>;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ToolDefinition {
+    pub name: String,
+    pub description: String,
+    /// JSON schema of the arguments object
+    pub parameters: Value,
+    /// Minimum permission of the chatter that triggered the reply
+    #[serde(skip)]
+    pub permission: Permission,
+    /// The tool only works if the bot is a moderator of the channel
+    #[serde(skip)]
+    pub requires_bot_moderator: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub tool: String,
+    #[serde(default)]
+    pub arguments: Value,
+}
+
+impl ToolCall {
+    /// Parses a model answer made only of a tool call JSON object, optionally inside a code block
+    pub fn parse(answer: &str) -> Option<Self> {
+        let answer = answer
+            .trim()
+            .trim_start_matches("```json")
+            .trim_start_matches("```")
+            .trim_end_matches("```")
+            .trim();
+        if !answer.starts_with('{') {
+            return None;
+        }
+        serde_json::from_str(answer).ok()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Tool {
+    definition: ToolDefinition,
+    handler: ToolHandler,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ToolRegistry {
+    tools: Vec<Tool>,
+}
+
+impl ToolRegistry {
+    pub fn new() -> Self {
+        ToolRegistry::default()
+    }
+
+    /// Registry with all the bot built-in tools
+    pub fn with_builtin_tools() -> Self {
+        let mut registry = ToolRegistry::new();
+        registry.register(
+            ToolDefinition {
+                name: "uptime".into(),
+                description: "Tells since when and for how long the bot has been running".into(),
+                parameters: json!({ "type": "object", "properties": {} }),
+                permission: Permission::Everyone,
+                requires_bot_moderator: false,
+            },
+            uptime
+        );
+        registry.register(
+            ToolDefinition {
+                name: "room_state".into(),
+                description: "Reads the current chat settings: emote-only, followers-only, slow mode, subs-only".into(),
+                parameters: json!({ "type": "object", "properties": {} }),
+                permission: Permission::Everyone,
+                requires_bot_moderator: false,
+            },
+            room_state
+        );
+        registry.register(
+            ToolDefinition {
+                name: "add_quote".into(),
+                description: "Saves a memorable quote from the chat or the streamer".into(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "author": { "type": "string", "description": "Who said it", "maxLength": 50 },
+                        "quote": { "type": "string", "description": "The quote text", "maxLength": 300 }
+                    },
+                    "required": ["author", "quote"]
+                }),
+                permission: Permission::Subscriber,
+                requires_bot_moderator: false,
+            },
+            add_quote
+        );
+        registry.register(
+            ToolDefinition {
+                name: "set_tts_voice".into(),
+                description: "Changes the text to speech voice".into(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "locale": {
+                            "type": "string",
+                            "description": "Voice locale, e.g. it-IT or en-US",
+                            "pattern": "^[a-z]{2,3}(-[A-Za-z]{2,4})?$"
+                        },
+                        "gender": { "type": "string", "enum": ["Male", "Female"] }
+                    },
+                    "required": ["locale"]
+                }),
+                permission: Permission::Vip,
+                requires_bot_moderator: false,
+            },
+            set_tts_voice
+        );
+        registry.register(
+            ToolDefinition {
+                name: "timeout_user".into(),
+                description: "Times out a spammer for the given number of seconds".into(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "user": { "type": "string", "description": "Nickname of the user", "pattern": "^[A-Za-z0-9_]{1,25}$" },
+                        "seconds": { "type": "integer", "minimum": 1, "maximum": 1209600 },
+                        "reason": { "type": "string", "maxLength": 200 }
+                    },
+                    "required": ["user", "seconds"]
+                }),
+                permission: Permission::Moderator,
+                requires_bot_moderator: true,
+            },
+            timeout_user
+        );
+        registry
+    }
+
+    pub fn register(&mut self, definition: ToolDefinition, handler: ToolHandler) {
+        self.tools.retain(|tool| tool.definition.name != definition.name);
+        self.tools.push(Tool { definition, handler });
+    }
+
+    pub fn definitions(&self) -> Vec<&ToolDefinition> {
+        self.tools
+            .iter()
+            .map(|tool| &tool.definition)
+            .collect()
+    }
+
+    /// Instructions appended to the system prompt describing how to call the tools
+    pub fn system_prompt(&self) -> String {
+        format!(
+            "You can take actions using tools. To use a tool, answer ONLY with a JSON object \
+            like {{\"tool\": \"<name>\", \"arguments\": {{...}}}} and nothing else. \
+            The tool result will be sent back to you as '[tool:<name>]: result', \
+            then answer the user normally. Available tools:\n{}",
+            serde_json::to_string_pretty(&self.definitions()).unwrap_or_default()
+        )
+    }
+
+    /// Runs a tool call on behalf of `sender`, checking permissions first
+    pub async fn call(&self, args: Arc<Args>, sender: &str, call: ToolCall) -> Result<String> {
+        let tool = self.tools
+            .iter()
+            .find(|tool| tool.definition.name == call.tool)
+            .ok_or_else(|| anyhow!("unknown tool {}", call.tool))?;
+
+        let permission = args.chat_state.get_user_permission(sender).await;
+        if permission < tool.definition.permission {
+            return Err(
+                anyhow!(
+                    "{} is not allowed to use {}, requires {:?}",
+                    sender,
+                    call.tool,
+                    tool.definition.permission
+                )
+            );
+        }
+        if
+            tool.definition.requires_bot_moderator &&
+            args.chat_state.get_bot_permission().await < Permission::Moderator
+        {
+            return Err(anyhow!("the bot is not a moderator of the channel"));
+        }
+        // The arguments come from the model, nothing reaches a handler unchecked
+        validate_arguments(&tool.definition.parameters, &call.arguments)?;
+
+        (tool.handler)(args, call.arguments).await
+    }
+}
+
+/// Checks `arguments` against the subset of JSON schema the tool definitions use:
+/// required and unknown properties, types, enum, pattern, maxLength, minimum and maximum.
+/// Strings with control characters are always refused.
+pub fn validate_arguments(schema: &Value, arguments: &Value) -> Result<()> {
+    let empty = serde_json::Map::new();
+    let arguments = match arguments {
+        Value::Object(arguments) => arguments,
+        Value::Null => &empty,
+        _ => {
+            return Err(anyhow!("the arguments must be an object"));
+        }
+    };
+    let properties = schema["properties"].as_object().unwrap_or(&empty);
+
+    for required in schema["required"].as_array().into_iter().flatten() {
+        let required = required.as_str().unwrap_or_default();
+        if !arguments.contains_key(required) {
+            return Err(anyhow!("missing argument {}", required));
+        }
+    }
+    for (name, value) in arguments {
+        let property = properties.get(name).ok_or_else(|| anyhow!("unknown argument {}", name))?;
+        match property["type"].as_str() {
+            Some("string") => {
+                let value = value.as_str().ok_or_else(|| anyhow!("argument {} must be a string", name))?;
+                if value.chars().any(|c| c.is_control()) {
+                    return Err(anyhow!("argument {} contains control characters", name));
+                }
+                if let Some(max_length) = property["maxLength"].as_u64() {
+                    if value.chars().count() as u64 > max_length {
+                        return Err(anyhow!("argument {} is longer than {} characters", name, max_length));
+                    }
+                }
+                if let Some(pattern) = property["pattern"].as_str() {
+                    if !Regex::new(pattern)?.is_match(value) {
+                        return Err(anyhow!("argument {} does not match {}", name, pattern));
+                    }
+                }
+            }
+            Some("integer") => {
+                let value = value.as_i64().ok_or_else(|| anyhow!("argument {} must be an integer", name))?;
+                if property["minimum"].as_i64().is_some_and(|minimum| value < minimum) {
+                    return Err(anyhow!("argument {} is below {}", name, property["minimum"]));
+                }
+                if property["maximum"].as_i64().is_some_and(|maximum| value > maximum) {
+                    return Err(anyhow!("argument {} is above {}", name, property["maximum"]));
+                }
+            }
+            Some(kind) => {
+                return Err(anyhow!("argument {} has the unsupported type {}", name, kind));
+            }
+            None => {}
+        }
+        if let Some(allowed) = property["enum"].as_array() {
+            if !allowed.contains(value) {
+                return Err(anyhow!("argument {} must be one of {}", name, property["enum"]));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn string_argument(arguments: &Value, name: &str) -> Result<String> {
+    arguments[name]
+        .as_str()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .ok_or_else(|| anyhow!("missing argument {}", name))
+}
+
+fn uptime(args: Arc<Args>, _arguments: Value) -> BoxFuture<'static, Result<String>> {
+    (async move {
+        let started_at = args.chat_state.started_at();
+        let uptime = Local::now() - started_at;
+        Ok(
+            format!(
+                "running since {}, uptime {}h {}m",
+                started_at.format("%Y-%m-%d %H:%M"),
+                uptime.num_hours(),
+                uptime.num_minutes() % 60
+            )
+        )
+    }).boxed()
+}
+
+fn room_state(args: Arc<Args>, _arguments: Value) -> BoxFuture<'static, Result<String>> {
+    (async move { Ok(serde_json::to_string(&args.chat_state.get_room_state().await)?) }).boxed()
+}
+
+fn add_quote(_args: Arc<Args>, arguments: Value) -> BoxFuture<'static, Result<String>> {
+    (async move {
+        let author = string_argument(&arguments, "author")?;
+        let quote = string_argument(&arguments, "quote")?;
+        let line = format!("{} \"{}\" - {}\n", Local::now().format("%Y-%m-%d"), quote, author);
+
+        let saved = tokio::fs::read_to_string(QUOTES_FILE).await.unwrap_or_default().lines().count();
+        let mut quotes = tokio::fs::OpenOptions::new().create(true).append(true).open(QUOTES_FILE).await?;
+        quotes.write_all(line.as_bytes()).await?;
+        Ok(format!("quote #{} saved", saved + 1))
+    }).boxed()
+}
+
+fn set_tts_voice(args: Arc<Args>, arguments: Value) -> BoxFuture<'static, Result<String>> {
+    (async move {
+        let locale = string_argument(&arguments, "locale")?;
+        let gender = string_argument(&arguments, "gender").ok();
+        args.events.publish(BotEvent::TtsControl(TtsControl::SetVoice {
+            locale: locale.clone(),
+            gender: gender.clone(),
+        }));
+        Ok(format!("voice change requested: {} {}", locale, gender.unwrap_or_default()))
+    }).boxed()
+}
+
+fn timeout_user(args: Arc<Args>, arguments: Value) -> BoxFuture<'static, Result<String>> {
+    (async move {
+        let user = string_argument(&arguments, "user")?;
+        let seconds = arguments["seconds"]
+            .as_u64()
+            .ok_or_else(|| anyhow!("missing argument seconds"))?;
+        let reason = string_argument(&arguments, "reason").unwrap_or_default();
+        let broadcaster_id = args.chat_state
+            .get_room_state().await
+            .get("room-id")
+            .cloned()
+            .ok_or_else(|| anyhow!("the channel id is not known yet"))?;
+        let moderator_id = args.bot_info.get_user_id().await;
+        if moderator_id.is_empty() {
+            return Err(anyhow!("the bot user id is not known yet"));
+        }
+        // Twitch ignores the /timeout chat command since 2023
+        let user_id = args.helix.user_id(&user).await?;
+        args.helix.timeout(&broadcaster_id, &moderator_id, &user_id, seconds, &reason).await?;
+        Ok(format!("{} timed out for {} seconds", user, seconds))
+    }).boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timeout_schema() -> Value {
+        ToolRegistry::with_builtin_tools()
+            .definitions()
+            .into_iter()
+            .find(|definition| definition.name == "timeout_user")
+            .unwrap()
+            .parameters
+            .clone()
+    }
+
+    #[test]
+    fn valid_arguments_pass() {
+        let arguments = json!({ "user": "spam_bot42", "seconds": 600, "reason": "spam" });
+        assert!(validate_arguments(&timeout_schema(), &arguments).is_ok());
+    }
+
+    #[test]
+    fn injected_arguments_are_refused() {
+        let schema = timeout_schema();
+        for arguments in [
+            json!({ "user": "victim\r\nPRIVMSG #channel :hi", "seconds": 600 }),
+            json!({ "user": "victim other", "seconds": 600 }),
+            json!({ "user": "victim", "seconds": 0 }),
+            json!({ "user": "victim", "seconds": 99999999 }),
+            json!({ "user": "victim", "seconds": "600" }),
+            json!({ "user": "victim", "seconds": 600, "reason": "spam\n/ban streamer" }),
+            json!({ "user": "victim", "seconds": 600, "extra": true }),
+            json!({ "seconds": 600 }),
+        ] {
+            assert!(validate_arguments(&schema, &arguments).is_err(), "{} accepted", arguments);
+        }
+    }
+
+    #[test]
+    fn enum_is_enforced() {
+        let schema = json!({ "type": "object", "properties": { "gender": { "type": "string", "enum": ["Male", "Female"] } } });
+        assert!(validate_arguments(&schema, &json!({ "gender": "Male" })).is_ok());
+        assert!(validate_arguments(&schema, &json!({ "gender": "Robot" })).is_err());
+    }
+}
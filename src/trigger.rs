@@ -0,0 +1,216 @@
+// Decide which chat messages are answered by the LLM and which are only kept as context
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::time::{ Duration, Instant };
+
+use rand::Rng;
+use regex::Regex;
+use serde::{ Deserialize, Serialize };
+
+use crate::colors::Colorize;
+use crate::config_manager::ConfigManager;
+use crate::irc_parser::IrcMessage;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TriggerConfig {
+    /// Reply when the bot name is mentioned in the message
+    pub on_mention: bool,
+    /// Reply when the message starts with this prefix, empty to disable
+    pub command_prefix: String,
+    /// Probability (0.0 - 1.0) of replying to any other message
+    pub reply_probability: f64,
+    /// Reply to the first message a chatter ever sends in the channel
+    pub on_first_message: bool,
+    /// Reply when any of these words is found in the message (case insensitive)
+    pub keywords: Vec<String>,
+    /// Reply when any of these regular expressions matches the message
+    pub patterns: Vec<String>,
+    /// Seconds a single user has to wait between two replies
+    pub user_cooldown: u64,
+    /// Seconds between two replies, whoever asks
+    pub global_cooldown: u64,
+}
+
+impl ConfigManager for TriggerConfig {}
+
+impl Default for TriggerConfig {
+    fn default() -> Self {
+        TriggerConfig {
+            on_mention: true,
+            command_prefix: "!ai".into(),
+            reply_probability: 0.0,
+            on_first_message: true,
+            keywords: vec![],
+            patterns: vec![],
+            user_cooldown: 30,
+            global_cooldown: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TriggerReason {
+    Mention,
+    Command,
+    Random,
+    FirstMessage,
+    Keyword(String),
+    Pattern(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trigger {
+    pub reason: TriggerReason,
+    /// Message text, without the command prefix if one was used
+    pub text: String,
+}
+
+/// `word` appears in `text` on its own, e.g. "@botox hi" but not "@botoxfan hi"
+fn contains_word(text: &str, word: &str) -> bool {
+    let is_name_char = |c: char| c.is_alphanumeric() || c == '_';
+    text.match_indices(word).any(|(start, _)| {
+        let before = text[..start].chars().next_back();
+        let after = text[start + word.len()..].chars().next();
+        !before.is_some_and(is_name_char) && !after.is_some_and(is_name_char)
+    })
+}
+
+#[derive(Debug)]
+pub struct TriggerEngine {
+    config: TriggerConfig,
+    patterns: Vec<Regex>,
+    last_user_reply: HashMap<String, Instant>,
+    last_reply: Option<Instant>,
+}
+
+impl TriggerEngine {
+    /// Patterns that are not valid regular expressions are logged and skipped
+    pub fn new(config: TriggerConfig) -> Self {
+        let patterns = config.patterns
+            .iter()
+            .filter_map(|pattern| {
+                Regex::new(pattern)
+                    .inspect_err(|err| println!("{} Pattern {:?} skipped: {}", "[TRIGGER]".red(), pattern, err))
+                    .ok()
+            })
+            .collect::<Vec<Regex>>();
+
+        TriggerEngine {
+            config,
+            patterns,
+            last_user_reply: HashMap::new(),
+            last_reply: None,
+        }
+    }
+
+    /// Returns the matching trigger if the message should be answered,
+    /// None if it should only be added to the context
+    pub fn evaluate(&mut self, bot_name: &str, message: &IrcMessage) -> Option<Trigger> {
+        let trigger = self.match_rules(bot_name, message)?;
+
+        let now = Instant::now();
+        let user = &message.context.sender;
+
+        if let Some(last) = self.last_reply {
+            if now.duration_since(last) < Duration::from_secs(self.config.global_cooldown) {
+                println!("[TRIGGER] {:?} from {} ignored, global cooldown", trigger.reason, user);
+                return None;
+            }
+        }
+        if let Some(last) = self.last_user_reply.get(user) {
+            if now.duration_since(*last) < Duration::from_secs(self.config.user_cooldown) {
+                println!("[TRIGGER] {:?} from {} ignored, user cooldown", trigger.reason, user);
+                return None;
+            }
+        }
+
+        self.last_reply = Some(now);
+        self.last_user_reply.insert(user.clone(), now);
+        Some(trigger)
+    }
+
+    fn match_rules(&self, bot_name: &str, message: &IrcMessage) -> Option<Trigger> {
+        let text = message.payload.as_str();
+        let lowercase_text = text.to_lowercase();
+        let trigger = |reason| Some(Trigger { reason, text: text.to_string() });
+
+        let prefix = &self.config.command_prefix;
+        // "!ai hello" but not "!aidan hello"
+        let command = text
+            .strip_prefix(prefix.as_str())
+            .filter(|rest| !prefix.is_empty() && (rest.is_empty() || rest.starts_with(char::is_whitespace)));
+        if let Some(rest) = command {
+            return Some(Trigger {
+                reason: TriggerReason::Command,
+                text: rest.trim().to_string(),
+            });
+        }
+
+        if
+            self.config.on_mention &&
+            !bot_name.is_empty() &&
+            contains_word(&lowercase_text, &bot_name.to_lowercase())
+        {
+            return trigger(TriggerReason::Mention);
+        }
+
+        if self.config.on_first_message && message.tag("first-msg") == Some("1") {
+            return trigger(TriggerReason::FirstMessage);
+        }
+
+        if
+            let Some(keyword) = self.config.keywords
+                .iter()
+                .find(|keyword| lowercase_text.contains(&keyword.to_lowercase()))
+        {
+            return trigger(TriggerReason::Keyword(keyword.clone()));
+        }
+
+        if let Some(pattern) = self.patterns.iter().find(|pattern| pattern.is_match(text)) {
+            return trigger(TriggerReason::Pattern(pattern.to_string()));
+        }
+
+        if rand::thread_rng().gen_bool(self.config.reply_probability.clamp(0.0, 1.0)) {
+            return trigger(TriggerReason::Random);
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::irc_parser::Context;
+
+    fn engine() -> TriggerEngine {
+        TriggerEngine::new(TriggerConfig { on_first_message: false, ..TriggerConfig::default() })
+    }
+
+    fn reason(text: &str) -> Option<TriggerReason> {
+        let message = IrcMessage::new(HashMap::new(), Context::new("alice", "PRIVMSG", "#channel"), text);
+        engine().match_rules("BoTOX", &message).map(|trigger| trigger.reason)
+    }
+
+    #[test]
+    fn command_prefix_needs_a_word_boundary() {
+        assert_eq!(reason("!ai hello"), Some(TriggerReason::Command));
+        assert_eq!(reason("!ai"), Some(TriggerReason::Command));
+        assert_eq!(reason("!aidan hello"), None);
+    }
+
+    #[test]
+    fn bad_patterns_are_skipped() {
+        let config = TriggerConfig { patterns: vec!["(unclosed".into(), "^hello".into()], ..TriggerConfig::default() };
+        assert_eq!(TriggerEngine::new(config).patterns.len(), 1);
+    }
+
+    #[test]
+    fn mention_needs_a_word_boundary() {
+        assert_eq!(reason("hi @botox!"), Some(TriggerReason::Mention));
+        assert_eq!(reason("BoTOX what time is it"), Some(TriggerReason::Mention));
+        assert_eq!(reason("hi @botoxfan"), None);
+        assert_eq!(reason("hi @the_botox"), None);
+    }
+}
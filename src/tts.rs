@@ -1,18 +1,525 @@
 #![allow(dead_code)]
-use std::sync::Arc;
-use msedge_tts::{
-    tts::{ client::connect_async, SpeechConfig },
-    voice::{ get_voices_list_async, Voice },
-};
-use anyhow::Result;
+use std::{ collections::HashMap, sync::Arc, time::Duration };
+use anyhow::{ anyhow, Result };
 use rand::Rng;
+use serde::{ Deserialize, Serialize };
+use tokio::{ sync::Mutex, task::JoinHandle };
 
+use crate::audio_cache::{ AudioCache, AudioCacheConfig };
+use crate::audio_sink::{ AudioClip, AudioSink, AudioSinkConfig };
+use crate::chat_envelope::ChatEnvelope;
+use crate::chat_state::Permission;
+use crate::colors::Colorize;
+use crate::config_manager::ConfigManager;
+use crate::com::QueuePolicy;
+use crate::event_bus::{ BotEvent, Subscriber, Topic };
+use crate::lang_detect::LanguageDetector;
+use crate::ssml::{ self, Prosody };
+use crate::tts_normalize::{ self, NormalizeConfig, TextNormalizer };
+use crate::tts_engine::{ SpeechAudio, SynthesisRequest, TtsBackend, TtsEngine, TtsEngineConfig, VoiceInfo };
+use crate::tts_playback::{ Playback, PlaybackConfig, TtsBacklog };
+use crate::voice_list::VoiceListCache;
+use crate::voice_query::{ NoVoiceMatch, VoiceQuery };
+use crate::voice_assign::{ stable_hash, VoiceAssignments };
 use crate::Args;
 
+/// Which chat messages are read, any of the rules is enough
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TtsTriggerConfig {
+    /// Read every chat message of the allowed chatters
+    pub all_chat: bool,
+    /// Chatters with at least this role are read through the chat or the command
+    pub min_permission: Permission,
+    /// Messages starting with this are read without it, e.g. !say hello, empty to disable
+    pub command: String,
+    /// Cheers of at least this many bits are read whatever the role, 0 to disable
+    pub min_bits: u32,
+    /// Redemptions of these channel point rewards (custom-reward-id tag) are read whatever the role
+    pub reward_ids: Vec<String>,
+}
+
+impl Default for TtsTriggerConfig {
+    fn default() -> Self {
+        TtsTriggerConfig {
+            all_chat: false,
+            min_permission: Permission::Everyone,
+            command: "!say".into(),
+            min_bits: 100,
+            reward_ids: Vec::new(),
+        }
+    }
+}
+
+/// Prefixes of the global Twitch cheermotes, lowercase
+const CHEERMOTE_PREFIXES: &[&str] = &[
+    "cheer", "doodlecheer", "biblethump", "cheerwhal", "corgo", "scoops", "uni", "showlove", "party",
+    "seemsgood", "pride", "kappa", "frankerz", "heyguys", "dansgame", "elegiggle", "trihard", "kreygasm",
+    "4head", "swiftrage", "notlikethis", "failfish", "vohiyo", "pjsalt", "mrdestructoid", "bday", "ripcheer",
+    "shamrock", "bitboss", "streamlabs", "muxy", "holidaycheer", "goal", "anon", "charity",
+];
+
+/// A cheermote prefix followed by the bits, e.g. Cheer100
+fn is_cheermote(word: &str) -> bool {
+    let prefix = word.trim_end_matches(|c: char| c.is_ascii_digit());
+    prefix.len() < word.len() && CHEERMOTE_PREFIXES.contains(&prefix.to_lowercase().as_str())
+}
+
+/// Keeps the words of the text for which `keep(index, word)` is true, joined by single spaces.
+/// The emote ranges are character positions in the text, they move with their word.
+fn keep_words(message: &mut TtsMessage, keep: impl Fn(usize, &str) -> bool) {
+    let mut words: Vec<(usize, String)> = Vec::new();
+    let mut in_word = false;
+    for (index, c) in message.text.chars().enumerate() {
+        match (c.is_whitespace(), in_word) {
+            (true, _) => in_word = false,
+            (false, false) => {
+                words.push((index, c.to_string()));
+                in_word = true;
+            }
+            (false, true) => words.last_mut().unwrap().1.push(c),
+        }
+    }
+
+    let mut text = String::new();
+    let mut emotes = Vec::new();
+    let mut length = 0;
+    for (index, (start, word)) in words.iter().enumerate() {
+        if !keep(index, word) {
+            continue;
+        }
+        if length > 0 {
+            text.push(' ');
+            length += 1;
+        }
+        let end = start + word.chars().count();
+        emotes.extend(
+            message.emotes
+                .iter()
+                .filter(|(emote_start, emote_end)| emote_start >= start && *emote_end < end)
+                .map(|(emote_start, emote_end)| (length + emote_start - start, length + emote_end - start))
+        );
+        text.push_str(word);
+        length += word.chars().count();
+    }
+    message.text = text;
+    message.emotes = emotes;
+}
+
+impl TtsTriggerConfig {
+    /// `message` ready to be queued, None if no rule asks to read it
+    pub fn apply(&self, mut message: TtsMessage) -> Option<TtsMessage> {
+        if !message.reward_id.is_empty() && self.reward_ids.contains(&message.reward_id) {
+            message.trigger = TtsTrigger::Reward(message.reward_id.clone());
+            return Some(message);
+        }
+        if self.min_bits > 0 && message.bits >= self.min_bits {
+            message.trigger = TtsTrigger::Bits(message.bits);
+            // Cheermotes such as Cheer100 are in the text of the cheers, they are not worth reading
+            keep_words(&mut message, |_, word| !is_cheermote(word));
+            return Some(message);
+        }
+        if message.permission < self.min_permission {
+            return None;
+        }
+        if !self.command.is_empty() {
+            let command = message.text.split_whitespace().next().unwrap_or_default();
+            if command.eq_ignore_ascii_case(&self.command) {
+                keep_words(&mut message, |index, _| index > 0);
+                // The command alone is not read, not even as chat
+                if message.text.is_empty() {
+                    return None;
+                }
+                message.trigger = TtsTrigger::Command;
+                return Some(message);
+            }
+        }
+        if self.all_chat {
+            message.trigger = TtsTrigger::Chat;
+            return Some(message);
+        }
+        None
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TtsConfig {
+    /// Read nothing at all when false
+    pub enabled: bool,
+    /// Read the chat messages
+    pub speak_chat: bool,
+    /// Read the replies of the bot. Their markup is only approximated with prosody, so it works with
+    /// every voice and engine: *word* is read louder and higher, [whisper] softer, slower and lower,
+    /// not with the whispering style some Azure voices have (msedge-tts can not send mstts:express-as)
+    pub speak_bot_replies: bool,
+    /// Chat messages read
+    pub triggers: TtsTriggerConfig,
+
+    /// Voices given to the chatters, e.g. gender = "Male" and kind = "neural"
+    pub default_voice: VoiceQuery,
+    /// Short name of the voice reading the bot replies, e.g. it-IT-DiegoNeural,
+    /// empty to pick it like the chatters ones
+    pub bot_voice: String,
+    /// Voice of a chatter by nickname, over the one chosen with !myvoice
+    pub user_voices: HashMap<String, String>,
+    /// Pick the voice locale from the language of each message
+    pub detect_language: bool,
+    /// Detections less confident than this (0.0 - 1.0) use the fallback locale
+    pub min_detection_confidence: f64,
+    /// Locale used when the language is unknown or has no voice
+    pub fallback_locale: String,
+    /// Voice locale of each detected language
+    pub language_locales: HashMap<String, String>,
+
+    /// Rate, pitch and volume of every voice
+    pub prosody: Prosody,
+    /// Prosody of a voice by short name, over the global one
+    pub voice_prosody: HashMap<String, Prosody>,
+    /// Prosody of a chatter by nickname, over the voice one
+    pub user_prosody: HashMap<String, Prosody>,
+    /// Clean up of the text before it is read
+    pub normalize: NormalizeConfig,
+
+    /// Limits of the messages waiting to be read
+    pub playback: PlaybackConfig,
+    /// Synthesizer reading the messages
+    pub engine: TtsEngineConfig,
+    /// Output format asked to the engine, e.g. audio-24khz-48kbitrate-mono-mp3 or riff-24khz-16bit-mono-pcm (WAV)
+    pub audio_format: String,
+    /// Hours before the cached voice list is downloaded again
+    pub voice_list_refresh_hours: u64,
+    /// Seconds between two attempts to download the voice list
+    pub voice_list_retry_seconds: u64,
+    /// Attempts after a failed synthesis before the message is skipped
+    pub synthesis_retries: u32,
+    /// Synthesized clips kept on disk
+    pub cache: AudioCacheConfig,
+    /// Where the synthesized audio goes
+    pub sinks: Vec<AudioSinkConfig>,
+}
+
+impl TtsConfig {
+    /// Prosody of `voice` reading a message of `user`
+    pub fn prosody_for(&self, voice: &str, user: &str) -> Prosody {
+        let voice_prosody = self.voice_prosody
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(voice))
+            .map(|(_, prosody)| *prosody)
+            .unwrap_or_default();
+        let user_prosody = self.user_prosody.get(&user.to_lowercase()).copied().unwrap_or_default();
+        self.prosody.merge(&voice_prosody).merge(&user_prosody)
+    }
+
+    /// `message` ready to be queued, None if it should not be read
+    pub fn accept(&self, mut message: TtsMessage) -> Option<TtsMessage> {
+        if message.from_bot {
+            message.trigger = TtsTrigger::BotReply;
+            return self.speak_bot_replies.then_some(message);
+        }
+        if !self.speak_chat {
+            return None;
+        }
+        self.triggers.apply(message)
+    }
+}
+
+impl ConfigManager for TtsConfig {}
+
+impl Default for TtsConfig {
+    fn default() -> Self {
+        TtsConfig {
+            enabled: true,
+            speak_chat: true,
+            speak_bot_replies: true,
+            triggers: TtsTriggerConfig::default(),
+            default_voice: VoiceQuery::new().gender("Male"),
+            bot_voice: "it-IT-DiegoNeural".into(),
+            user_voices: HashMap::new(),
+            detect_language: true,
+            min_detection_confidence: 0.1,
+            fallback_locale: "it-IT".into(),
+            language_locales: HashMap::from([
+                ("en".into(), "en-US".into()),
+                ("it".into(), "it-IT".into()),
+                ("es".into(), "es-ES".into()),
+                ("fr".into(), "fr-FR".into()),
+                ("de".into(), "de-DE".into()),
+                ("pt".into(), "pt-BR".into()),
+            ]),
+            prosody: Prosody { rate: Some(0), pitch: Some(0), volume: Some(0) },
+            voice_prosody: HashMap::new(),
+            user_prosody: HashMap::new(),
+            normalize: NormalizeConfig::default(),
+            playback: PlaybackConfig::default(),
+            engine: TtsEngineConfig::Edge,
+            audio_format: "audio-24khz-48kbitrate-mono-mp3".into(),
+            voice_list_refresh_hours: 24,
+            voice_list_retry_seconds: 60,
+            synthesis_retries: 2,
+            cache: AudioCacheConfig::default(),
+            sinks: vec![AudioSinkConfig::Directory { path: "tts_audio".into() }],
+        }
+    }
+}
+
+/// Chooses the voice of each message: chatters keep their voice, the bot has its own
+#[derive(Debug)]
+struct VoiceSelector {
+    config: TtsConfig,
+    detector: LanguageDetector,
+    voices: TTSConfigs,
+    assignments: VoiceAssignments,
+    bot_voice: Option<TTSSpeech>,
+}
+
+impl VoiceSelector {
+    fn new(config: TtsConfig, voices: TTSConfigs, assignments: VoiceAssignments) -> Self {
+        let bot_voice = voices.find(&config.bot_voice);
+        VoiceSelector {
+            config,
+            detector: LanguageDetector::new(),
+            voices,
+            assignments,
+            bot_voice,
+        }
+    }
+
+    /// Replaces the voice list, keeping the bot voice if it is still there
+    fn set_voices(&mut self, voices: TTSConfigs) {
+        let bot_voice = match &self.bot_voice {
+            Some(voice) => voice.short_name(),
+            None => self.config.bot_voice.clone(),
+        };
+        self.bot_voice = voices.find(&bot_voice);
+        self.voices = voices;
+    }
+
+    fn locale_of(&self, text: &str) -> String {
+        if !self.config.detect_language {
+            return self.config.fallback_locale.clone();
+        }
+        self.detector
+            .detect(text)
+            .filter(|detected| (detected.confidence as f64) >= self.config.min_detection_confidence)
+            .and_then(|detected| self.config.language_locales.get(&detected.language).cloned())
+            .unwrap_or(self.config.fallback_locale.clone())
+    }
+
+    /// Voice of `user` for `locale`, assigned from the hash of the user on first use
+    async fn user_voice_for_locale(&mut self, user: &str, locale: &str) -> Option<TTSSpeech> {
+        if let Some(voice) = self.assignments.for_locale(user, locale).and_then(|name| self.voices.find(name)) {
+            return Some(voice);
+        }
+        let query = self.config.default_voice.merge(&VoiceQuery::new().language(locale));
+        let voice = match self.voices.query(&query) {
+            Ok(candidates) => candidates.pick(stable_hash(user))?,
+            Err(err) => {
+                println!("[TTS] {}", err);
+                return None;
+            }
+        };
+        let name = voice.short_name();
+        println!("[TTS] Voice of {} for {}: {}", user, locale, name);
+
+        self.assignments.set_for_locale(user, locale, &name);
+        if let Err(err) = self.assignments.save().await {
+            println!("{} Failed to save voice assignments: {}", "[TTS]".red(), err);
+        }
+        Some(voice)
+    }
+
+    /// Voice reading `text`, the normalized text of `message`
+    async fn voice_for(&mut self, message: &TtsMessage, text: &str) -> Option<TTSSpeech> {
+        if message.from_bot {
+            if let Some(voice) = &self.bot_voice {
+                return Some(voice.clone());
+            }
+        }
+
+        let configured = self.config.user_voices
+            .iter()
+            .find(|(user, _)| user.eq_ignore_ascii_case(&message.sender))
+            .and_then(|(_, name)| self.voices.find(name));
+        if configured.is_some() {
+            return configured;
+        }
+
+        let user = message.voice_key();
+        if let Some(voice) = self.assignments.chosen(&user).and_then(|name| self.voices.find(name)) {
+            return Some(voice);
+        }
+
+        let locale = self.locale_of(text);
+        let fallback_locale = self.config.fallback_locale.clone();
+        match self.user_voice_for_locale(&user, &locale).await {
+            Some(voice) => Some(voice),
+            None => self.user_voice_for_locale(&user, &fallback_locale).await,
+        }
+    }
+
+    /// Replaces the bot voice with one matching `locale` and `gender`
+    fn set_bot_voice(&mut self, locale: &str, gender: Option<&str>) -> Result<TTSSpeech, NoVoiceMatch> {
+        let mut query = self.config.default_voice.merge(&VoiceQuery::new().language(locale));
+        if let Some(gender) = gender {
+            query = query.gender(gender);
+        }
+        let candidates = self.voices.query(&query)?;
+        let voice = candidates.random().ok_or_else(|| query.explain(&[]))?;
+        self.bot_voice = Some(voice.clone());
+        Ok(voice)
+    }
+
+    /// Sets the voice chosen by a chatter, None to go back to the assigned ones
+    async fn set_user_voice(&mut self, user: &str, name: Option<&str>) -> Result<Option<TTSSpeech>> {
+        let voice = match name {
+            Some(name) => {
+                let voice = match self.voices.find(name) {
+                    Some(voice) => voice,
+                    None => {
+                        // A part of the name is enough when only one voice has it
+                        let candidates = self.voices.query(&VoiceQuery::new().name(name))?;
+                        if candidates.len() > 1 {
+                            let names = candidates.tts_configs
+                                .iter()
+                                .take(5)
+                                .map(|voice| voice.short_name())
+                                .collect::<Vec<String>>()
+                                .join(", ");
+                            return Err(anyhow!("{} voices match {}: {}", candidates.len(), name, names));
+                        }
+                        candidates.random().ok_or_else(|| anyhow!("unknown voice {}", name))?
+                    }
+                };
+                Some(voice)
+            }
+            None => None,
+        };
+        self.assignments.set_chosen(user, voice.as_ref().map(|voice| voice.short_name()));
+        self.assignments.save().await?;
+        Ok(voice)
+    }
+
+    fn user_voice(&self, user: &str) -> Option<&str> {
+        self.assignments.chosen(user)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct TTSSpeech {
-    voice_config: Arc<Voice>,
-    speech_config: Arc<SpeechConfig>,
+    voice_config: Arc<VoiceInfo>,
+}
+
+impl TTSSpeech {
+    pub fn short_name(&self) -> String {
+        self.voice_config.short_name.clone().unwrap_or(self.voice_config.name.clone())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum TtsTrigger {
+    #[default]
+    Chat,
+    Command,
+    Bits(u32),
+    /// Channel point redemption, with the reward id
+    Reward(String),
+    BotReply,
+}
+
+/// A text to read aloud and who it comes from
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TtsMessage {
+    pub sender: String,
+    pub display_name: String,
+    pub user_id: String,
+    /// Chat color of the sender, e.g. #FF4500, empty if never set
+    pub color: String,
+    pub text: String,
+    /// Character ranges of the Twitch emotes in the text
+    pub emotes: Vec<(usize, usize)>,
+    pub permission: Permission,
+    /// Bits cheered with the message
+    pub bits: u32,
+    /// Channel point reward redeemed with the message, empty if none
+    pub reward_id: String,
+    /// Why the message is read
+    pub trigger: TtsTrigger,
+    /// Messages with a higher priority are read first
+    pub priority: u32,
+    /// A reply of the bot, read with the bot voice
+    pub from_bot: bool,
+    /// Correlation id of the chat message, or of the question for a bot reply, 0 if none
+    pub correlation_id: u64,
+}
+
+impl TtsMessage {
+    pub fn from_envelope(envelope: &ChatEnvelope) -> Self {
+        let message = &envelope.message;
+        TtsMessage {
+            sender: envelope.author.login.clone(),
+            display_name: envelope.author.display_name.clone(),
+            user_id: envelope.author.user_id.clone(),
+            color: envelope.author.color.clone(),
+            text: message.payload.clone(),
+            emotes: tts_normalize::parse_emote_ranges(message.tag("emotes").unwrap_or_default()),
+            permission: envelope.author.permission,
+            bits: message
+                .tag("bits")
+                .and_then(|bits| bits.parse().ok())
+                .unwrap_or_default(),
+            reward_id: message.tag("custom-reward-id").unwrap_or_default().to_string(),
+            trigger: TtsTrigger::Chat,
+            priority: envelope.priority,
+            from_bot: false,
+            correlation_id: envelope.correlation_id,
+        }
+    }
+
+    pub fn from_bot(bot_name: &str, text: &str) -> Self {
+        TtsMessage {
+            sender: bot_name.to_string(),
+            display_name: bot_name.to_string(),
+            text: text.to_string(),
+            from_bot: true,
+            ..TtsMessage::default()
+        }
+    }
+
+    /// Key of the sender in the voice assignments
+    pub fn voice_key(&self) -> String {
+        match self.user_id.is_empty() {
+            true => self.sender.to_lowercase(),
+            false => self.user_id.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TtsControl {
+    /// Change the voice of the bot replies
+    SetVoice {
+        locale: String,
+        gender: Option<String>,
+    },
+    /// Voice chosen by a chatter with !myvoice, None to reset it
+    SetUserVoice {
+        user: String,
+        user_id: String,
+        voice: Option<String>,
+    },
+    /// Tell a chatter which voice they chose
+    UserVoiceInfo {
+        user: String,
+        user_id: String,
+    },
+    /// Stop the clip being played
+    Skip,
+    /// Stop reading the queued messages after the current one
+    Pause,
+    Resume,
+    /// Drop every queued message
+    Clear,
+    /// Tell the chat how many messages are queued
+    Status,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -64,78 +571,368 @@ pub struct TTSConfigs {
 }
 
 impl TTSConfigs {
-    pub async fn new() -> Self {
-        let voices = get_voices_list_async().await
-            .unwrap()
+    pub fn new(voices: Vec<VoiceInfo>) -> Self {
+        let voices = voices
             .into_iter()
-            .map(|voice| {
-                TTSSpeech {
-                    speech_config: Arc::new(SpeechConfig::from(&voice)),
-                    voice_config: Arc::new(voice),
-                }
-            })
+            .map(|voice| TTSSpeech { voice_config: Arc::new(voice) })
             .collect::<Vec<TTSSpeech>>();
         TTSConfigs {
             tts_configs: voices,
         }
     }
 
-    pub fn filter_gender(&self, gender: TTSGender) -> TTSConfigs {
+    /// Voices matching `query`, or why there are none
+    pub fn query(&self, query: &VoiceQuery) -> Result<TTSConfigs, NoVoiceMatch> {
         let voices = self.tts_configs
             .iter()
-            .filter(|voice| voice.voice_config.gender == Some(gender.into()))
-            .map(|voice| voice.clone())
+            .filter(|voice| query.matches(&voice.voice_config))
+            .cloned()
             .collect::<Vec<TTSSpeech>>();
-
-        TTSConfigs {
-            tts_configs: voices
-                .iter()
-                .map(|voice| voice.clone())
-                .collect::<Vec<TTSSpeech>>(),
+        if voices.is_empty() {
+            return Err(query.explain(&self.voice_infos()));
         }
+        Ok(TTSConfigs {
+            tts_configs: voices,
+        })
     }
 
-    pub fn filter_locale(&self, locale: &str) -> TTSConfigs {
-        let voices = self.tts_configs
+    fn voice_infos(&self) -> Vec<VoiceInfo> {
+        self.tts_configs
             .iter()
-            .filter(|voice| voice.voice_config.locale == Some(locale.into()))
-            .map(|voice| voice.clone())
-            .collect::<Vec<TTSSpeech>>();
+            .map(|voice| voice.voice_config.as_ref().clone())
+            .collect()
+    }
 
-        TTSConfigs {
-            tts_configs: voices
-                .iter()
-                .map(|voice| voice.clone())
-                .collect::<Vec<TTSSpeech>>(),
+    pub fn len(&self) -> usize {
+        self.tts_configs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tts_configs.is_empty()
+    }
+
+    /// Voice by short name (it-IT-DiegoNeural) or full name, case insensitive
+    pub fn find(&self, name: &str) -> Option<TTSSpeech> {
+        if name.is_empty() {
+            return None;
+        }
+        self.tts_configs
+            .iter()
+            .find(|voice| {
+                voice.voice_config.name.eq_ignore_ascii_case(name) ||
+                    voice.voice_config.short_name
+                        .as_deref()
+                        .is_some_and(|short_name| short_name.eq_ignore_ascii_case(name))
+            })
+            .cloned()
+    }
+
+    /// Deterministic choice, the same `seed` always gives the same voice of the list
+    pub fn pick(&self, seed: u64) -> Option<TTSSpeech> {
+        if self.tts_configs.is_empty() {
+            return None;
         }
+        Some(self.tts_configs[(seed % (self.tts_configs.len() as u64)) as usize].clone())
     }
 
-    pub fn random(&self) -> TTSSpeech {
+    pub fn random(&self) -> Option<TTSSpeech> {
+        if self.tts_configs.is_empty() {
+            return None;
+        }
         let mut rng = rand::thread_rng();
         let index = rng.gen_range(0..self.tts_configs.len());
-        self.tts_configs[index].clone()
+        Some(self.tts_configs[index].clone())
+    }
+}
+
+/// Longest wait for the engine to synthesize a clip
+const SYNTHESIS_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Synthesizes `request`, trying again `retries` times, None if the engine keeps failing
+async fn synthesize(engine: &mut TtsBackend, request: &SynthesisRequest, retries: u32) -> Option<SpeechAudio> {
+    for attempt in 0..=retries {
+        if attempt > 0 {
+            tokio::time::sleep(Duration::from_millis(500 * (attempt as u64))).await;
+        }
+        match tokio::time::timeout(SYNTHESIS_TIMEOUT, engine.synthesize(request)).await {
+            Ok(Ok(audio)) => {
+                return Some(audio);
+            }
+            Ok(Err(err)) => println!("{} Synthesis failed (attempt {}): {}", "[TTS]".red(), attempt + 1, err),
+            Err(_) => println!("{} Synthesis timed out (attempt {})", "[TTS]".red(), attempt + 1),
+        }
+    }
+    None
+}
+
+/// Next event, the controls and bot replies before the chat
+async fn next_event(chat: &Subscriber, events: &Subscriber) -> Arc<BotEvent> {
+    tokio::select! {
+        biased;
+        event = events.recv() => event,
+        event = chat.recv() => event,
     }
 }
 
 pub async fn start(args: Arc<Args>) -> Result<()> {
-    let voices = TTSConfigs::new().await;
+    let config = TtsConfig::load_config::<TtsConfig>(TtsConfig::default(), "tts_config.toml").await?;
+    if !config.enabled {
+        println!("[TTS] Disabled");
+        return Ok(());
+    }
+    let chat = args.events.subscribe("tts", &[Topic::Chat]);
+    // Controls and bot replies get their own queue, a chat burst cannot evict them
+    let events = args.events.subscribe_with("tts-events", &[Topic::LlmReply, Topic::TtsControl], 64, QueuePolicy::DropNewest);
+    let mut engine = TtsBackend::from_config(&config.engine);
+    let mut voice_list = VoiceListCache::load(
+        engine.name(),
+        Duration::from_secs(config.voice_list_refresh_hours * 3600),
+        Duration::from_secs(config.voice_list_retry_seconds)
+    );
+    // Without the engine the cached list, even old, is still good
+    let voices = voice_list.refresh(&mut engine).await.unwrap_or(voice_list.voices());
+    if voices.is_empty() {
+        println!("{} No voices available yet, messages are skipped until the engine answers", "[TTS]".red());
+    }
+    let voices = TTSConfigs::new(voices);
 
-    let voice = voices.filter_gender(TTSGender::Male).filter_locale("it-IT").random();
-    println!("{:#?}", voice);
+    let mut sinks = Vec::new();
+    for sink_config in &config.sinks {
+        match AudioSink::from_config(sink_config).await {
+            Ok(sink) => sinks.push(sink),
+            Err(err) => println!("{} Sink {:?} disabled: {}", "[TTS][SINK]".red(), sink_config, err),
+        }
+    }
+    let audio_format = config.audio_format.clone();
+    let settings = config.clone();
+    let normalizer = TextNormalizer::new(config.normalize.clone())?;
+    let mut backlog = TtsBacklog::new(config.playback.clone());
+    let mut playback = Playback::new(sinks, &config.playback);
+    let synthesis_retries = config.synthesis_retries;
+    let cache = AudioCache::load(config.cache.clone()).unwrap_or_else(|err| {
+        println!("{} Cache disabled: {}", "[TTS][CACHE]".red(), err);
+        AudioCache::disabled()
+    });
+    let assignments = VoiceAssignments::load().await.unwrap_or_else(|err| {
+        println!("{} Voice assignments not loaded: {}", "[TTS]".red(), err);
+        VoiceAssignments::default()
+    });
+    let mut selector = VoiceSelector::new(config, voices, assignments);
+    let mut sequence = 0;
+
+    // Synthesis runs in the background with the playback, the controls are served meanwhile
+    let engine_name = engine.name();
+    let engine = Arc::new(Mutex::new(engine));
+    let cache = Arc::new(Mutex::new(cache));
+    let mut synthesis: Option<JoinHandle<Option<AudioClip>>> = None;
 
-    let mut tts = connect_async().await?;
     loop {
+        if !playback.is_playing() && synthesis.is_none() {
+            if let Some(ret_val) = backlog.pop() {
+                if let Some(voices) = voice_list.refresh(&mut *engine.lock().await).await {
+                    selector.set_voices(TTSConfigs::new(voices));
+                }
+                let text = normalizer.clean(&ret_val.text, &ret_val.emotes);
+                let Some(voice) = selector.voice_for(&ret_val, &text).await else {
+                    println!("[TTS] No voice available, skipping {:?}", ret_val);
+                    continue;
+                };
+                let locale = voice.voice_config.locale.clone().unwrap_or_default();
+                let Some(text) = normalizer.finish(&text, &locale) else {
+                    println!("[TTS] Nothing to read in {:?}", ret_val.text);
+                    continue;
+                };
+                let prosody = settings.prosody_for(&voice.short_name(), &ret_val.sender);
+                let (ssml, caption) = match ret_val.from_bot {
+                    true => (ssml::from_markup(&text), ssml::strip_markup(&ret_val.text)),
+                    false => (ssml::from_text(&text), ret_val.text.clone()),
+                };
+                let request = SynthesisRequest {
+                    text: ssml::strip_markup(&text),
+                    ssml,
+                    voice: voice.voice_config.name.clone(),
+                    rate: prosody.rate(),
+                    pitch: prosody.pitch(),
+                    volume: prosody.volume(),
+                    format: audio_format.clone(),
+                };
+                let key = AudioCache::key(
+                    &request.ssml,
+                    &format!("{}:{}", engine_name, request.voice),
+                    request.rate,
+                    request.pitch,
+                    request.volume,
+                    &request.format
+                );
+                println!("Request {:?}", ret_val);
+
+                let engine = engine.clone();
+                let cache = cache.clone();
+                let clip_sequence = sequence;
+                synthesis = Some(
+                    tokio::spawn(async move {
+                        let cached = cache.lock().await.get(&key).await;
+                        let audio = match cached {
+                            Some(audio) => audio,
+                            None => {
+                                let audio = synthesize(&mut *engine.lock().await, &request, synthesis_retries).await;
+                                let Some(audio) = audio else {
+                                    println!("{} Skipping {:?}", "[TTS]".red(), ret_val.text);
+                                    return None;
+                                };
+                                cache.lock().await.put(&key, &audio).await;
+                                audio
+                            }
+                        };
+                        Some(AudioClip {
+                            sequence: clip_sequence,
+                            format: audio.format,
+                            bytes: audio.bytes,
+                            text: caption,
+                            voice: voice.short_name(),
+                            speaker: ret_val.display_name,
+                            color: ret_val.color,
+                            duration: audio.duration,
+                        })
+                    })
+                );
+                sequence += 1;
+                continue;
+            }
+        }
+
         tokio::select! {
 
-        ret_val = args.tts_message_queue.recv() => {
-            let audio = tts.synthesize(&ret_val, &voice.speech_config.clone()).await?;
-            println!("Request {:?}", ret_val);
-            println!("Response {:?
-            }", audio.audio_metadata);
-            
+        _ = playback.finished(), if playback.is_playing() => {}
+
+        clip = async { synthesis.as_mut().unwrap().await }, if synthesis.is_some() => {
+            synthesis = None;
+            if let Ok(Some(clip)) = clip {
+                playback.play(clip);
+            }
+        }
+
+        event = next_event(&chat, &events) => {
+            let control = match &*event {
+                BotEvent::Chat(envelope) => {
+                    if let Some(ret_val) = settings.accept(TtsMessage::from_envelope(envelope)) {
+                        backlog.push(ret_val);
+                    }
+                    continue;
+                }
+                BotEvent::LlmReply { bot_name, speech, reply_to, .. } => {
+                    let message = TtsMessage {
+                        correlation_id: reply_to.as_ref().map(|envelope| envelope.correlation_id).unwrap_or_default(),
+                        ..TtsMessage::from_bot(bot_name, speech)
+                    };
+                    if let Some(ret_val) = settings.accept(message) {
+                        backlog.push(ret_val);
+                    }
+                    continue;
+                }
+                BotEvent::TtsControl(control) => control.clone(),
+                _ => continue,
+            };
+            // The TTS reads the stream of the main channel
+            let channel = args.bot_info.get_main_channel().await;
+            match control {
+                TtsControl::SetVoice { locale, gender } => {
+                    match selector.set_bot_voice(&locale, gender.as_deref()) {
+                        Ok(voice) => println!("[TTS] Bot voice changed to {}", voice.short_name()),
+                        Err(err) => println!("[TTS] Bot voice not changed, {}", err),
+                    }
+                }
+                TtsControl::SetUserVoice { user, user_id, voice } => {
+                    let key = TtsMessage { sender: user.clone(), user_id, ..TtsMessage::default() }.voice_key();
+                    let answer = match selector.set_user_voice(&key, voice.as_deref()).await {
+                        Ok(Some(voice)) => format!("@{} your voice is now {}", user, voice.short_name()),
+                        Ok(None) => format!("@{} your voice now follows your language", user),
+                        Err(err) => format!("@{} {}", user, err),
+                    };
+                    args.events.publish(BotEvent::SendChat { channel: channel.clone(), text: answer });
+                }
+                TtsControl::UserVoiceInfo { user, user_id } => {
+                    let key = TtsMessage { sender: user.clone(), user_id, ..TtsMessage::default() }.voice_key();
+                    let answer = match selector.user_voice(&key) {
+                        Some(voice) => format!("@{} your voice is {}, !myvoice reset to go back to automatic", user, voice),
+                        None => format!("@{} your voice follows your language, !myvoice <voice> to choose one, e.g. it-IT-DiegoNeural", user),
+                    };
+                    args.events.publish(BotEvent::SendChat { channel: channel.clone(), text: answer });
+                }
+                TtsControl::Skip => {
+                    if let Some(pending) = synthesis.take() {
+                        pending.abort();
+                        _ = pending.await;
+                        // The aborted request may have left the rest of its response on the connection
+                        engine.lock().await.disconnect();
+                        println!("[TTS] Clip skipped before playing");
+                    } else if playback.skip().await {
+                        println!("[TTS] Clip skipped");
+                    }
+                }
+                TtsControl::Pause => {
+                    backlog.set_paused(true);
+                    args.events.publish(BotEvent::SendChat { channel: channel.clone(), text: "TTS paused".to_string() });
+                }
+                TtsControl::Resume => {
+                    backlog.set_paused(false);
+                    args.events.publish(BotEvent::SendChat { channel: channel.clone(), text: "TTS resumed".to_string() });
+                }
+                TtsControl::Clear => {
+                    let dropped = backlog.clear();
+                    args.events.publish(BotEvent::SendChat { channel: channel.clone(), text: format!("TTS queue cleared, {} messages dropped", dropped) });
+                }
+                TtsControl::Status => {
+                    let state = if backlog.is_paused() { "paused" } else { "running" };
+                    args.events.publish(BotEvent::SendChat { channel: channel.clone(), text: format!("TTS {}, {} messages queued", state, backlog.len()) });
+                }
+            }
         }
 
+    }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    fn message(text: &str) -> TtsMessage {
+        TtsMessage { text: text.into(), ..TtsMessage::default() }
     }
+
+    fn cheer(text: &str, bits: u32) -> TtsMessage {
+        TtsMessage { bits, ..message(text) }
+    }
+
+    #[test]
+    fn only_known_cheermotes_are_stripped() {
+        let cheered = TtsTriggerConfig::default().apply(cheer("Cheer100 gg win2 on my ps5 Kappa50", 150)).unwrap();
+        assert_eq!(cheered.text, "gg win2 on my ps5");
+    }
+
+    #[test]
+    fn emotes_move_with_their_words() {
+        let config = TtsTriggerConfig::default();
+        let said = config.apply(TtsMessage { emotes: vec![(5, 9)], ..message("!say Kappa hello") }).unwrap();
+        assert_eq!(said.text, "Kappa hello");
+        assert_eq!(said.emotes, vec![(0, 4)]);
+
+        let cheered = config.apply(TtsMessage { emotes: vec![(9, 13)], ..cheer("Cheer100 Kappa  hi", 100) }).unwrap();
+        assert_eq!(cheered.text, "Kappa hi");
+        assert_eq!(cheered.emotes, vec![(0, 4)]);
+    }
+
+    #[test]
+    fn command_without_text_is_ignored() {
+        let config = TtsTriggerConfig { all_chat: true, ..TtsTriggerConfig::default() };
+        assert!(config.apply(message("!say")).is_none());
+        assert!(config.apply(message("!say   ")).is_none());
+        assert_eq!(config.apply(message("!say hello")).map(|message| message.text), Some("hello".into()));
+    }
+
+    #[test]
+    fn chat_is_not_read_by_default() {
+        assert!(TtsTriggerConfig::default().apply(message("hello there")).is_none());
     }
 }
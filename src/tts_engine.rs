@@ -0,0 +1,564 @@
+// Speech synthesizers behind one interface: Microsoft Edge online voices, a local command, a test tone
+#![allow(dead_code)]
+
+use std::{ f32::consts::PI, process::Stdio, time::Duration };
+
+use anyhow::{ anyhow, Result };
+use msedge_tts::{
+    tts::{
+        client::{ connect_async, MSEdgeTTSClientAsync, SynthesizedAudio },
+        stream::{ msedge_tts_split_async, SynthesizedResponse },
+        AudioMetadata,
+        SpeechConfig,
+    },
+    voice::{ get_voices_list_async, Voice },
+};
+use futures::{ future::BoxFuture, AsyncRead, AsyncWrite };
+use serde::{ Deserialize, Serialize };
+use tokio::{ io::{ AsyncReadExt, AsyncWriteExt }, process::Command, sync::mpsc };
+
+/// Format of the WAV clips made by the local engines
+const WAV_FORMAT: &str = "riff-24khz-16bit-mono-pcm";
+const WAV_SAMPLE_RATE: u32 = 24000;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct VoiceInfo {
+    /// Name the engine knows the voice by
+    pub name: String,
+    /// e.g. it-IT-DiegoNeural
+    pub short_name: Option<String>,
+    /// Male or Female
+    pub gender: Option<String>,
+    /// e.g. it-IT
+    pub locale: Option<String>,
+    pub friendly_name: Option<String>,
+    /// Content categories and personalities, e.g. News, Friendly
+    pub tags: Vec<String>,
+}
+
+impl VoiceInfo {
+    /// Edge neural voices have it in their name, e.g. it-IT-DiegoNeural
+    pub fn is_neural(&self) -> bool {
+        self.name.contains("Neural") || self.short_name.as_deref().is_some_and(|name| name.contains("Neural"))
+    }
+}
+
+impl From<Voice> for VoiceInfo {
+    fn from(voice: Voice) -> Self {
+        let tags = voice.voice_tag
+            .map(|tag| {
+                tag.content_categories
+                    .unwrap_or_default()
+                    .into_iter()
+                    .chain(tag.voice_personalities.unwrap_or_default())
+                    .collect()
+            })
+            .unwrap_or_default();
+        VoiceInfo {
+            name: voice.name,
+            short_name: voice.short_name,
+            gender: voice.gender,
+            locale: voice.locale,
+            friendly_name: voice.friendly_name,
+            tags,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SynthesisRequest {
+    /// Plain text, for the engines not reading SSML
+    pub text: String,
+    /// SSML body of the text, escaped and with the inline markup
+    pub ssml: String,
+    pub voice: String,
+    /// Speed change in percent
+    pub rate: i32,
+    /// Pitch change in Hz
+    pub pitch: i32,
+    /// Volume change in percent
+    pub volume: i32,
+    /// Audio format wanted, engines that cannot produce it return their own
+    pub format: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpeechAudio {
+    pub format: String,
+    pub bytes: Vec<u8>,
+    pub duration: Duration,
+}
+
+pub trait TtsEngine {
+    fn name(&self) -> &'static str;
+
+    /// Drops the connection to the service, if any, after a synthesis was cancelled
+    fn disconnect(&mut self) {}
+
+    async fn list_voices(&mut self) -> Result<Vec<VoiceInfo>>;
+
+    async fn synthesize(&mut self, request: &SynthesisRequest) -> Result<SpeechAudio>;
+
+    /// Sends the audio to `chunks` while it is synthesized, returns the whole clip at the end
+    async fn stream(
+        &mut self,
+        request: &SynthesisRequest,
+        chunks: mpsc::UnboundedSender<Vec<u8>>
+    ) -> Result<SpeechAudio> {
+        let audio = self.synthesize(request).await?;
+        _ = chunks.send(audio.bytes.clone());
+        Ok(audio)
+    }
+}
+
+/// Connected Edge client, whatever socket msedge-tts opened it on
+trait EdgeClient: Send {
+    fn synthesize<'a>(&'a mut self, ssml: &'a str, config: &'a SpeechConfig) -> BoxFuture<'a, Result<SynthesizedAudio>>;
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> EdgeClient for MSEdgeTTSClientAsync<T> {
+    fn synthesize<'a>(&'a mut self, ssml: &'a str, config: &'a SpeechConfig) -> BoxFuture<'a, Result<SynthesizedAudio>> {
+        Box::pin(async move { Ok(MSEdgeTTSClientAsync::synthesize(self, ssml, config).await?) })
+    }
+}
+
+/// Clip length from the Edge word boundaries, in 100ns ticks
+fn edge_duration(metadata: &[AudioMetadata]) -> Duration {
+    let ticks = metadata
+        .iter()
+        .map(|metadata| metadata.offset + metadata.duration)
+        .max()
+        .unwrap_or_default();
+    Duration::from_nanos(ticks * 100)
+}
+
+fn edge_speech_config(request: &SynthesisRequest) -> SpeechConfig {
+    SpeechConfig {
+        voice_name: request.voice.clone(),
+        audio_format: request.format.clone(),
+        pitch: request.pitch,
+        rate: request.rate,
+        volume: request.volume,
+    }
+}
+
+/// Microsoft Edge read aloud service, needs to be online
+#[derive(Default)]
+pub struct EdgeEngine {
+    client: Option<Box<dyn EdgeClient>>,
+}
+
+impl std::fmt::Debug for EdgeEngine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EdgeEngine").field("connected", &self.client.is_some()).finish()
+    }
+}
+
+impl TtsEngine for EdgeEngine {
+    fn name(&self) -> &'static str {
+        "edge"
+    }
+
+    fn disconnect(&mut self) {
+        self.client = None;
+    }
+
+    async fn list_voices(&mut self) -> Result<Vec<VoiceInfo>> {
+        Ok(get_voices_list_async().await?.into_iter().map(VoiceInfo::from).collect())
+    }
+
+    async fn synthesize(&mut self, request: &SynthesisRequest) -> Result<SpeechAudio> {
+        // Out of its slot while in use: after an error, or a call dropped by a timeout or a skip,
+        // the socket may be dead or hold the rest of the old response, the next clip opens a new one
+        let mut client = match self.client.take() {
+            Some(client) => client,
+            None => Box::new(connect_async().await?),
+        };
+        // msedge-tts puts the text inside its prosody element as it is
+        let audio = client.synthesize(&request.ssml, &edge_speech_config(request)).await?;
+        self.client = Some(client);
+        Ok(SpeechAudio {
+            duration: edge_duration(&audio.audio_metadata),
+            format: audio.audio_format,
+            bytes: audio.audio_bytes,
+        })
+    }
+
+    async fn stream(
+        &mut self,
+        request: &SynthesisRequest,
+        chunks: mpsc::UnboundedSender<Vec<u8>>
+    ) -> Result<SpeechAudio> {
+        // A streamed clip has its own connection, the reader cannot be shared
+        let (mut sender, mut reader) = msedge_tts_split_async().await?;
+        sender.send(&request.ssml, &edge_speech_config(request)).await?;
+
+        let mut bytes = Vec::new();
+        let mut metadata = Vec::new();
+        loop {
+            match reader.read().await? {
+                Some(SynthesizedResponse::AudioBytes(chunk)) => {
+                    _ = chunks.send(chunk.clone());
+                    bytes.extend(chunk);
+                }
+                Some(SynthesizedResponse::AudioMetadata(chunk)) => metadata.extend(chunk),
+                None => break,
+            }
+            if !reader.can_read().await {
+                break;
+            }
+        }
+        Ok(SpeechAudio {
+            format: request.format.clone(),
+            bytes,
+            duration: edge_duration(&metadata),
+        })
+    }
+}
+
+/// Duration of a PCM WAV clip from its header
+fn wav_duration(bytes: &[u8]) -> Option<Duration> {
+    if bytes.len() < 44 || &bytes[0..4] != b"RIFF" {
+        return None;
+    }
+    let byte_rate = u32::from_le_bytes(bytes[28..32].try_into().ok()?);
+    if byte_rate == 0 {
+        return None;
+    }
+    Some(Duration::from_secs_f64(((bytes.len() - 44) as f64) / (byte_rate as f64)))
+}
+
+/// Rough length of `text` read aloud, for the clips without a known duration
+fn estimated_duration(text: &str) -> Duration {
+    Duration::from_millis((text.chars().count() as u64) * 70)
+}
+
+/// Local synthesizer program, e.g. piper or espeak-ng, writing the audio to stdout.
+/// The arguments can contain {text}, {voice}, {rate}, {pitch} and {volume},
+/// without {text} the text is written to its stdin, the safest choice.
+/// A {text} argument gets a "--" before it, and a text starting with '-' a leading space.
+#[derive(Debug)]
+pub struct CommandEngine {
+    program: String,
+    args: Vec<String>,
+    format: String,
+    voices: Vec<VoiceInfo>,
+}
+
+impl CommandEngine {
+    fn command(&self, request: &SynthesisRequest) -> (Command, bool) {
+        let reads_stdin = !self.args.iter().any(|arg| arg.contains("{text}"));
+        // Chat text must never be taken for an option, e.g. "-w /path" makes espeak write a file
+        let text = match request.text.starts_with('-') {
+            true => format!(" {}", request.text),
+            false => request.text.clone(),
+        };
+        let ends_options = self.args.iter().any(|arg| arg == "--");
+        let mut args = Vec::new();
+        for arg in &self.args {
+            if arg == "{text}" && !ends_options {
+                args.push("--".to_string());
+            }
+            args.push(
+                arg
+                    .replace("{text}", &text)
+                    .replace("{voice}", &request.voice)
+                    .replace("{rate}", &request.rate.to_string())
+                    .replace("{pitch}", &request.pitch.to_string())
+                    .replace("{volume}", &request.volume.to_string())
+            );
+        }
+        let mut command = Command::new(&self.program);
+        command
+            .args(args)
+            .stdin(if reads_stdin { Stdio::piped() } else { Stdio::null() })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true);
+        (command, reads_stdin)
+    }
+}
+
+impl TtsEngine for CommandEngine {
+    fn name(&self) -> &'static str {
+        "command"
+    }
+
+    async fn list_voices(&mut self) -> Result<Vec<VoiceInfo>> {
+        Ok(self.voices.clone())
+    }
+
+    async fn synthesize(&mut self, request: &SynthesisRequest) -> Result<SpeechAudio> {
+        // Nobody listens to the chunks
+        let (chunks, _) = mpsc::unbounded_channel();
+        self.stream(request, chunks).await
+    }
+
+    async fn stream(
+        &mut self,
+        request: &SynthesisRequest,
+        chunks: mpsc::UnboundedSender<Vec<u8>>
+    ) -> Result<SpeechAudio> {
+        let (mut command, reads_stdin) = self.command(request);
+        let mut child = command.spawn()?;
+        if reads_stdin {
+            let mut stdin = child.stdin.take().ok_or_else(|| anyhow!("no stdin for {}", self.program))?;
+            stdin.write_all(request.text.as_bytes()).await?;
+            // Closing stdin tells the synthesizer the text is over
+            drop(stdin);
+        }
+
+        let mut stdout = child.stdout.take().ok_or_else(|| anyhow!("no stdout for {}", self.program))?;
+        let mut bytes = Vec::new();
+        let mut buffer = [0u8; 8192];
+        loop {
+            let read = stdout.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            _ = chunks.send(buffer[..read].to_vec());
+            bytes.extend_from_slice(&buffer[..read]);
+        }
+
+        let status = child.wait().await?;
+        if !status.success() {
+            return Err(anyhow!("{} exited with {}", self.program, status));
+        }
+        Ok(SpeechAudio {
+            duration: wav_duration(&bytes).unwrap_or(estimated_duration(&request.text)),
+            format: self.format.clone(),
+            bytes,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Waveform {
+    Silence,
+    /// 440Hz tone
+    Sine,
+}
+
+/// Offline engine for tests: WAV clips as long as the text would take to read
+#[derive(Debug)]
+pub struct TestEngine {
+    waveform: Waveform,
+}
+
+impl TestEngine {
+    const LOCALES: [&'static str; 6] = ["en-US", "it-IT", "es-ES", "fr-FR", "de-DE", "pt-BR"];
+
+    fn wav(&self, duration: Duration) -> Vec<u8> {
+        let samples = ((duration.as_secs_f64() * (WAV_SAMPLE_RATE as f64)) as u32).max(1);
+        let data_size = samples * 2;
+
+        let mut wav = Vec::with_capacity(44 + (data_size as usize));
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + data_size).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        // PCM, mono
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&WAV_SAMPLE_RATE.to_le_bytes());
+        wav.extend_from_slice(&(WAV_SAMPLE_RATE * 2).to_le_bytes());
+        // Block align, bits per sample
+        wav.extend_from_slice(&2u16.to_le_bytes());
+        wav.extend_from_slice(&16u16.to_le_bytes());
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&data_size.to_le_bytes());
+
+        for sample in 0..samples {
+            let value = match self.waveform {
+                Waveform::Silence => 0i16,
+                Waveform::Sine => {
+                    let time = (sample as f32) / (WAV_SAMPLE_RATE as f32);
+                    ((2.0 * PI * 440.0 * time).sin() * 0.2 * (i16::MAX as f32)) as i16
+                }
+            };
+            wav.extend_from_slice(&value.to_le_bytes());
+        }
+        wav
+    }
+}
+
+impl TtsEngine for TestEngine {
+    fn name(&self) -> &'static str {
+        "test"
+    }
+
+    async fn list_voices(&mut self) -> Result<Vec<VoiceInfo>> {
+        Ok(
+            TestEngine::LOCALES.iter()
+                .flat_map(|locale| {
+                    ["Male", "Female"].map(|gender| VoiceInfo {
+                        name: format!("test-{}-{}", locale, gender),
+                        short_name: Some(format!("test-{}-{}", locale, gender)),
+                        gender: Some(gender.to_string()),
+                        locale: Some(locale.to_string()),
+                        friendly_name: Some(format!("Test {} {}", locale, gender)),
+                        tags: Vec::new(),
+                    })
+                })
+                .collect()
+        )
+    }
+
+    async fn synthesize(&mut self, request: &SynthesisRequest) -> Result<SpeechAudio> {
+        let duration = estimated_duration(&request.text).clamp(
+            Duration::from_millis(300),
+            Duration::from_secs(10)
+        );
+        Ok(SpeechAudio {
+            format: WAV_FORMAT.to_string(),
+            bytes: self.wav(duration),
+            duration,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TtsEngineConfig {
+    /// Microsoft Edge online voices
+    Edge,
+    /// Local synthesizer program writing the audio to stdout
+    Command {
+        program: String,
+        args: Vec<String>,
+        /// Format of the audio written by the program, e.g. riff-22khz-16bit-mono-pcm
+        format: String,
+        voices: Vec<VoiceInfo>,
+    },
+    /// Silence or a tone instead of speech, needs nothing installed
+    Test {
+        waveform: Waveform,
+    },
+}
+
+#[derive(Debug)]
+pub enum TtsBackend {
+    Edge(EdgeEngine),
+    Command(CommandEngine),
+    Test(TestEngine),
+}
+
+impl TtsBackend {
+    pub fn from_config(config: &TtsEngineConfig) -> Self {
+        match config {
+            TtsEngineConfig::Edge => TtsBackend::Edge(EdgeEngine::default()),
+            TtsEngineConfig::Command { program, args, format, voices } =>
+                TtsBackend::Command(CommandEngine {
+                    program: program.clone(),
+                    args: args.clone(),
+                    format: format.clone(),
+                    voices: voices.clone(),
+                }),
+            TtsEngineConfig::Test { waveform } => TtsBackend::Test(TestEngine { waveform: *waveform }),
+        }
+    }
+}
+
+impl TtsEngine for TtsBackend {
+    fn name(&self) -> &'static str {
+        match self {
+            TtsBackend::Edge(engine) => engine.name(),
+            TtsBackend::Command(engine) => engine.name(),
+            TtsBackend::Test(engine) => engine.name(),
+        }
+    }
+
+    fn disconnect(&mut self) {
+        match self {
+            TtsBackend::Edge(engine) => engine.disconnect(),
+            TtsBackend::Command(engine) => engine.disconnect(),
+            TtsBackend::Test(engine) => engine.disconnect(),
+        }
+    }
+
+    async fn list_voices(&mut self) -> Result<Vec<VoiceInfo>> {
+        match self {
+            TtsBackend::Edge(engine) => engine.list_voices().await,
+            TtsBackend::Command(engine) => engine.list_voices().await,
+            TtsBackend::Test(engine) => engine.list_voices().await,
+        }
+    }
+
+    async fn synthesize(&mut self, request: &SynthesisRequest) -> Result<SpeechAudio> {
+        match self {
+            TtsBackend::Edge(engine) => engine.synthesize(request).await,
+            TtsBackend::Command(engine) => engine.synthesize(request).await,
+            TtsBackend::Test(engine) => engine.synthesize(request).await,
+        }
+    }
+
+    async fn stream(
+        &mut self,
+        request: &SynthesisRequest,
+        chunks: mpsc::UnboundedSender<Vec<u8>>
+    ) -> Result<SpeechAudio> {
+        match self {
+            TtsBackend::Edge(engine) => engine.stream(request, chunks).await,
+            TtsBackend::Command(engine) => engine.stream(request, chunks).await,
+            TtsBackend::Test(engine) => engine.stream(request, chunks).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_backend() -> TtsBackend {
+        TtsBackend::from_config(&TtsEngineConfig::Test { waveform: Waveform::Sine })
+    }
+
+    fn request(text: &str) -> SynthesisRequest {
+        SynthesisRequest { text: text.into(), voice: "test-en-US-Female".into(), ..SynthesisRequest::default() }
+    }
+
+    #[tokio::test]
+    async fn test_engine_lists_a_voice_per_locale_and_gender() {
+        let voices = test_backend().list_voices().await.unwrap();
+        assert_eq!(voices.len(), TestEngine::LOCALES.len() * 2);
+        assert!(voices.iter().any(|voice| voice.locale.as_deref() == Some("it-IT") && voice.gender.as_deref() == Some("Male")));
+    }
+
+    #[tokio::test]
+    async fn test_engine_clip_lasts_as_long_as_its_wav() {
+        let mut engine = test_backend();
+        let audio = engine.synthesize(&request("hello chat, this is a test")).await.unwrap();
+        assert_eq!(audio.format, WAV_FORMAT);
+        let wav = wav_duration(&audio.bytes).unwrap();
+        assert!(wav.abs_diff(audio.duration) < Duration::from_millis(1));
+
+        let short = engine.synthesize(&request("")).await.unwrap();
+        assert_eq!(short.duration, Duration::from_millis(300));
+    }
+
+    #[test]
+    fn command_text_is_never_an_option() {
+        let engine = CommandEngine {
+            program: "espeak-ng".into(),
+            args: vec!["-v".into(), "{voice}".into(), "--stdout".into(), "{text}".into()],
+            format: WAV_FORMAT.into(),
+            voices: Vec::new(),
+        };
+        let (command, reads_stdin) = engine.command(&SynthesisRequest { voice: "it".into(), ..request("-w /tmp/owned") });
+        let args = command.as_std().get_args().collect::<Vec<_>>();
+        assert!(!reads_stdin);
+        assert_eq!(args, ["-v", "it", "--stdout", "--", " -w /tmp/owned"]);
+    }
+
+    #[tokio::test]
+    async fn test_engine_streams_the_whole_clip() {
+        let (chunks, mut received) = mpsc::unbounded_channel();
+        let audio = test_backend().stream(&request("streamed"), chunks).await.unwrap();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = received.recv().await {
+            bytes.extend(chunk);
+        }
+        assert_eq!(bytes, audio.bytes);
+    }
+}
@@ -0,0 +1,211 @@
+// Clean up of the chat text before it is read aloud
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+use regex::Regex;
+use serde::{ Deserialize, Serialize };
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NormalizeConfig {
+    /// Read in place of a Twitch emote, empty to drop the emotes
+    pub emote_replacement: String,
+    /// Read in place of a URL
+    pub url_replacement: String,
+    /// A character repeated more than this is cut down, LULLLLLL becomes LULL
+    pub max_repeated_chars: usize,
+    /// A word repeated more than this in a row is cut down
+    pub max_repeated_words: usize,
+    /// Chat abbreviations expanded for each language, e.g. en: brb -> be right back
+    pub abbreviations: HashMap<String, HashMap<String, String>>,
+    /// Longest text read, in characters, 0 for no limit
+    pub max_length: usize,
+}
+
+impl Default for NormalizeConfig {
+    fn default() -> Self {
+        let abbreviations = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs
+                .iter()
+                .map(|(abbreviation, expansion)| (abbreviation.to_string(), expansion.to_string()))
+                .collect()
+        };
+        NormalizeConfig {
+            emote_replacement: String::new(),
+            url_replacement: "link".into(),
+            max_repeated_chars: 2,
+            max_repeated_words: 2,
+            abbreviations: HashMap::from([
+                (
+                    "en".into(),
+                    abbreviations(
+                        &[
+                            ("brb", "be right back"),
+                            ("btw", "by the way"),
+                            ("gg", "good game"),
+                            ("idk", "I don't know"),
+                            ("imo", "in my opinion"),
+                            ("lol", "laughing out loud"),
+                            ("omg", "oh my god"),
+                            ("pls", "please"),
+                            ("ty", "thank you"),
+                            ("wp", "well played"),
+                        ],
+                    ),
+                ),
+                (
+                    "it".into(),
+                    abbreviations(
+                        &[
+                            ("cmq", "comunque"),
+                            ("nn", "non"),
+                            ("tvb", "ti voglio bene"),
+                            ("xché", "perché"),
+                            ("xke", "perché"),
+                            ("qlc", "qualcosa"),
+                            ("gg", "good game"),
+                        ],
+                    ),
+                ),
+                (
+                    "es".into(),
+                    abbreviations(&[("q", "que"), ("xq", "porque"), ("tmb", "también"), ("gg", "good game")]),
+                ),
+            ]),
+            max_length: 200,
+        }
+    }
+}
+
+/// Character ranges of the emotes in a message, from the `emotes` tag,
+/// e.g. "25:0-4,12-16/1902:6-10"
+pub fn parse_emote_ranges(tag: &str) -> Vec<(usize, usize)> {
+    let mut ranges = tag
+        .split('/')
+        .filter_map(|emote| emote.split_once(':'))
+        .flat_map(|(_, positions)| positions.split(','))
+        .filter_map(|range| {
+            let (start, end) = range.split_once('-')?;
+            Some((start.parse().ok()?, end.parse().ok()?))
+        })
+        .collect::<Vec<(usize, usize)>>();
+    ranges.sort();
+    ranges
+}
+
+#[derive(Debug)]
+pub struct TextNormalizer {
+    config: NormalizeConfig,
+    nick_prefix: Regex,
+    url: Regex,
+}
+
+impl TextNormalizer {
+    pub fn new(config: NormalizeConfig) -> Result<Self> {
+        Ok(TextNormalizer {
+            config,
+            nick_prefix: Regex::new(r"^\s*\[[^\]\s]+\]:\s*")?,
+            url: Regex::new(r"(?i)\b(?:https?://|www\.)\S+|\b[a-z0-9-]+(?:\.[a-z0-9-]+)*\.(?:com|net|org|tv|gg|io|it|ly|me)(?:/\S*)?\b")?,
+        })
+    }
+
+    fn replace_emotes(&self, text: &str, emotes: &[(usize, usize)]) -> String {
+        if emotes.is_empty() {
+            return text.to_string();
+        }
+        let mut result = String::new();
+        let mut emotes = emotes.iter().peekable();
+        let mut chars = text.chars().enumerate().peekable();
+        while let Some((index, c)) = chars.next() {
+            match emotes.peek() {
+                Some((start, end)) if index == *start => {
+                    result.push(' ');
+                    result.push_str(&self.config.emote_replacement);
+                    result.push(' ');
+                    while chars.next_if(|(index, _)| index <= end).is_some() {}
+                    emotes.next();
+                }
+                _ => result.push(c),
+            }
+        }
+        result
+    }
+
+    fn collapse_repeated_chars(&self, text: &str) -> String {
+        let mut result = String::with_capacity(text.len());
+        let mut previous = None;
+        let mut count = 0;
+        for c in text.chars() {
+            count = if Some(c.to_lowercase().to_string()) == previous { count + 1 } else { 1 };
+            previous = Some(c.to_lowercase().to_string());
+            // Numbers are read as they are, 1000 is not a repetition
+            if count <= self.config.max_repeated_chars.max(1) || c.is_ascii_digit() {
+                result.push(c);
+            }
+        }
+        result
+    }
+
+    fn collapse_repeated_words(&self, text: &str) -> String {
+        let mut words: Vec<&str> = Vec::new();
+        let mut count = 0;
+        for word in text.split_whitespace() {
+            let repeated = words.last().is_some_and(|last| last.eq_ignore_ascii_case(word));
+            count = if repeated { count + 1 } else { 1 };
+            if count <= self.config.max_repeated_words.max(1) {
+                words.push(word);
+            }
+        }
+        words.join(" ")
+    }
+
+    /// Removes what is never worth reading, before the language of the text is detected
+    pub fn clean(&self, text: &str, emotes: &[(usize, usize)]) -> String {
+        let text = self.replace_emotes(text, emotes);
+        let text = self.nick_prefix.replace(&text, "");
+        let replacement = format!(" {} ", self.config.url_replacement);
+        let text = self.url.replace_all(&text, regex::NoExpand(&replacement));
+        let text = self.collapse_repeated_chars(&text);
+        self.collapse_repeated_words(&text)
+    }
+
+    fn expand_abbreviations(&self, text: &str, locale: &str) -> String {
+        let language = locale.split('-').next().unwrap_or_default().to_lowercase();
+        let Some(abbreviations) = self.config.abbreviations.get(&language) else {
+            return text.to_string();
+        };
+        text.split_whitespace()
+            .map(|word| {
+                // Keep the punctuation around the abbreviation, "brb!" -> "be right back!"
+                let core = word.trim_matches(|c: char| !c.is_alphanumeric());
+                match abbreviations.get(&core.to_lowercase()) {
+                    Some(expansion) if !core.is_empty() => word.replacen(core, expansion, 1),
+                    _ => word.to_string(),
+                }
+            })
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    fn cap_length(&self, text: &str) -> String {
+        if self.config.max_length == 0 || text.chars().count() <= self.config.max_length {
+            return text.to_string();
+        }
+        let cut = text.chars().take(self.config.max_length).collect::<String>();
+        // Do not stop in the middle of a word
+        let cut = match cut.rfind(' ') {
+            Some(index) if index > 0 => &cut[..index],
+            _ => cut.as_str(),
+        };
+        cut.trim_end().to_string()
+    }
+
+    /// Adapts the cleaned text to the voice that reads it, None if nothing is left to read
+    pub fn finish(&self, text: &str, locale: &str) -> Option<String> {
+        let text = self.expand_abbreviations(text, locale);
+        let text = self.cap_length(&text);
+        let has_words = text.chars().any(|c| c.is_alphanumeric());
+        has_words.then_some(text)
+    }
+}
@@ -0,0 +1,239 @@
+// Backlog of the messages waiting to be read and playback of the current clip
+#![allow(dead_code)]
+
+use std::{ collections::VecDeque, sync::Arc, time::Duration };
+
+use futures::future::join_all;
+use serde::{ Deserialize, Serialize };
+use tokio::{ task::JoinHandle, time::Instant };
+
+use crate::audio_sink::{ AudioClip, AudioSink };
+use crate::colors::Colorize;
+use crate::tts::TtsMessage;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Make room dropping the lowest priority message waiting the longest
+    DropOldest,
+    /// Drop the incoming message, unless a queued one has a lower priority
+    DropNewest,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlaybackConfig {
+    /// Messages waiting to be read, 0 for no limit
+    pub max_backlog: usize,
+    /// What to drop when the backlog is full
+    pub overflow: OverflowPolicy,
+    /// Messages of the same chatter waiting to be read, 0 for no limit
+    pub max_per_user: usize,
+    /// Seconds a clip can play before it is cut, 0 for no limit
+    pub max_clip_duration: u64,
+}
+
+impl Default for PlaybackConfig {
+    fn default() -> Self {
+        PlaybackConfig {
+            max_backlog: 20,
+            overflow: OverflowPolicy::DropOldest,
+            max_per_user: 3,
+            max_clip_duration: 30,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TtsBacklog {
+    config: PlaybackConfig,
+    messages: VecDeque<TtsMessage>,
+    paused: bool,
+}
+
+impl TtsBacklog {
+    pub fn new(config: PlaybackConfig) -> Self {
+        TtsBacklog { config, messages: VecDeque::new(), paused: false }
+    }
+
+    /// Queues `message`, returns false if it has been dropped
+    pub fn push(&mut self, message: TtsMessage) -> bool {
+        if self.config.max_per_user > 0 && !message.from_bot {
+            let key = message.voice_key();
+            let queued = self.messages
+                .iter()
+                .filter(|queued| !queued.from_bot && queued.voice_key() == key)
+                .count();
+            if queued >= self.config.max_per_user {
+                println!("[TTS] {} has too many messages queued, dropping {:?}", message.sender, message.text);
+                return false;
+            }
+        }
+
+        if self.config.max_backlog > 0 && self.messages.len() >= self.config.max_backlog {
+            // Only messages of the lowest priority are dropped to make room
+            let lowest = self.messages
+                .iter()
+                .map(|queued| queued.priority)
+                .min()
+                .unwrap_or_default();
+            if message.priority < lowest {
+                println!("[TTS] Backlog full, dropping {:?}", message.text);
+                return false;
+            }
+            let position = match self.config.overflow {
+                OverflowPolicy::DropOldest => self.messages.iter().position(|queued| queued.priority == lowest),
+                OverflowPolicy::DropNewest if message.priority > lowest =>
+                    self.messages.iter().rposition(|queued| queued.priority == lowest),
+                OverflowPolicy::DropNewest => {
+                    println!("[TTS] Backlog full, dropping {:?}", message.text);
+                    return false;
+                }
+            };
+            if let Some(dropped) = position.and_then(|position| self.messages.remove(position)) {
+                println!("[TTS] Backlog full, dropping {:?}", dropped.text);
+            }
+        }
+
+        // After the messages of the same or higher priority, so equal ones keep their order
+        let position = self.messages
+            .iter()
+            .position(|queued| queued.priority < message.priority)
+            .unwrap_or(self.messages.len());
+        self.messages.insert(position, message);
+        true
+    }
+
+    /// Next message to read, None while paused
+    pub fn pop(&mut self) -> Option<TtsMessage> {
+        if self.paused {
+            return None;
+        }
+        self.messages.pop_front()
+    }
+
+    /// Empties the backlog, returns how many messages were dropped
+    pub fn clear(&mut self) -> usize {
+        let dropped = self.messages.len();
+        self.messages.clear();
+        dropped
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+}
+
+/// Plays one clip at a time on all the sinks at once, in the background so it can be skipped.
+/// A clip lasts at least its duration, whatever the sinks do with it.
+#[derive(Debug)]
+pub struct Playback {
+    sinks: Arc<Vec<AudioSink>>,
+    max_clip_duration: Option<Duration>,
+    current: Option<JoinHandle<()>>,
+}
+
+impl Playback {
+    pub fn new(sinks: Vec<AudioSink>, config: &PlaybackConfig) -> Self {
+        Playback {
+            sinks: Arc::new(sinks),
+            max_clip_duration: (config.max_clip_duration > 0).then(||
+                Duration::from_secs(config.max_clip_duration)
+            ),
+            current: None,
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.current.is_some()
+    }
+
+    pub fn play(&mut self, clip: AudioClip) {
+        let sinks = self.sinks.clone();
+        let max_clip_duration = self.max_clip_duration;
+        self.current = Some(
+            tokio::spawn(async move {
+                let play_all = async {
+                    let started = Instant::now();
+                    join_all(
+                        sinks.iter().map(|sink| async {
+                            if let Err(err) = sink.play(&clip).await {
+                                println!("{} {}", "[TTS][SINK]".red(), err);
+                            }
+                        })
+                    ).await;
+                    // Sinks like the directory only hand the clip over, the next one waits until this one is heard
+                    tokio::time::sleep_until(started + clip.duration).await;
+                };
+                let Some(max_clip_duration) = max_clip_duration else {
+                    play_all.await;
+                    return;
+                };
+                if tokio::time::timeout(max_clip_duration, play_all).await.is_err() {
+                    println!("[TTS] Clip {} longer than {:?}, cut", clip.sequence, max_clip_duration);
+                    for sink in sinks.iter() {
+                        sink.stop().await;
+                    }
+                }
+            })
+        );
+    }
+
+    /// Stops the current clip, returns false if nothing was playing
+    pub async fn skip(&mut self) -> bool {
+        let Some(current) = self.current.take() else {
+            return false;
+        };
+        current.abort();
+        for sink in self.sinks.iter() {
+            sink.stop().await;
+        }
+        true
+    }
+
+    /// Waits for the end of the current clip, never returns if nothing is playing
+    pub async fn finished(&mut self) {
+        match &mut self.current {
+            Some(current) => {
+                _ = current.await;
+                self.current = None;
+            }
+            None => std::future::pending().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn clip_lasts_its_duration_even_on_instant_sinks() {
+        let mut playback = Playback::new(Vec::new(), &PlaybackConfig::default());
+        let started = Instant::now();
+        playback.play(AudioClip {
+            sequence: 0,
+            format: "riff-24khz-16bit-mono-pcm".into(),
+            bytes: Vec::new(),
+            text: "hello".into(),
+            voice: "test".into(),
+            speaker: "alice".into(),
+            color: String::new(),
+            duration: Duration::from_millis(100),
+        });
+        playback.finished().await;
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert!(!playback.is_playing());
+    }
+}
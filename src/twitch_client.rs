@@ -1,7 +1,11 @@
 #![allow(dead_code)]
 
+use crate::chat_envelope::ChatEnvelope;
+use crate::chat_state::Permission;
 use crate::colors::Colorize;
+use crate::commands::{ self, ChatCommand };
 use crate::config_manager::ConfigManager;
+use crate::event_bus::{ BotEvent, Topic };
 use crate::irc_parser;
 use crate::Args;
 
@@ -22,6 +26,19 @@ pub struct TwitchClientConfig {
     pub channel: String,
     pub log_level: String,
     pub anti_idle: i32,
+    /// Answers of the bot are sent as Twitch replies to the question
+    #[serde(default)]
+    pub reply_in_thread: bool,
+    /// Client id of the Twitch application the token belongs to, needed by the moderation tools
+    #[serde(default)]
+    pub client_id: String,
+    /// Priority of the channel point redemptions, the cheers have their bits as priority
+    #[serde(default = "default_reward_priority")]
+    pub reward_priority: u32,
+}
+
+fn default_reward_priority() -> u32 {
+    1000
 }
 
 impl ConfigManager for TwitchClientConfig {}
@@ -35,6 +52,9 @@ impl Default for TwitchClientConfig {
             channel: "icsboyx".into(),
             log_level: "info".into(),
             anti_idle: 180,
+            reply_in_thread: false,
+            client_id: String::new(),
+            reward_priority: default_reward_priority(),
         }
     }
 }
@@ -51,6 +71,7 @@ impl<T: std::fmt::Display> WsMessageHandler for T {
 }
 
 pub async fn start(args: Arc<Args>) -> Result<()> {
+    let outgoing = args.events.subscribe("twitch", &[Topic::SendChat, Topic::LlmReply]);
     let config_file_name = "twitch_client_config.toml";
 
     // Load twitch Client configuration or use default values and write to config file
@@ -65,15 +86,18 @@ pub async fn start(args: Arc<Args>) -> Result<()> {
     let user_token = twitch_client_config.token;
     let user_nick = twitch_client_config.nick;
     let user_channel = twitch_client_config.channel;
+    args.helix.set_credentials(&twitch_client_config.client_id, &user_token).await;
+    let reply_in_thread = twitch_client_config.reply_in_thread;
+    let reward_priority = twitch_client_config.reward_priority;
 
     let (ws_stream, _response) = tokio_tungstenite::connect_async(server_address).await?;
     let (mut write, mut read) = ws_stream.split();
 
     println!("[DEBUG] Connected to Twitch, sending auth, nick, and join");
-    _ = write.send(format!("PASS oauth:{}", user_token).to_ws_text()).await?;
-    _ = write.send(format!("NICK {}", user_nick).to_ws_text()).await?;
-    _ = write.send(format!("JOIN #{}", user_channel).to_ws_text()).await?;
-    _ = write.send("CAP REQ :twitch.tv/tags".to_ws_text()).await?;
+    write.send(format!("PASS oauth:{}", user_token).to_ws_text()).await?;
+    write.send(format!("NICK {}", user_nick).to_ws_text()).await?;
+    write.send("CAP REQ :twitch.tv/tags twitch.tv/commands".to_ws_text()).await?;
+    write.send(format!("JOIN #{}", user_channel).to_ws_text()).await?;
 
     let ping_interval = tokio::time::interval(Duration::from_secs(180));
 
@@ -102,10 +126,33 @@ pub async fn start(args: Arc<Args>) -> Result<()> {
                             println!("[DEBUG] Bot Info: {:?}", args.bot_info);
                         }
                         "PRIVMSG" => {
-                                let payload = format!("[{}]: {}", irc_message.context.sender, irc_message.payload);
-                                args.ollama.send(payload.clone()).await;
-                                args.tts_message_queue.send(payload).await;
+                                let priority = match irc_message.tag("custom-reward-id") {
+                                    Some(_) => reward_priority,
+                                    None => irc_message.tag("bits").and_then(|bits| bits.parse().ok()).unwrap_or_default(),
+                                };
+                                let envelope = ChatEnvelope::new(irc_message.clone()).with_priority(priority);
+                                let sender = &envelope.author.login;
+                                args.chat_state.set_user_permission(sender, envelope.author.permission).await;
+                                args.chat_state.push_recent_chat(format!("[{}]: {}", sender, envelope.text())).await;
+                                if let Some(command) = ChatCommand::parse(envelope.text()) {
+                                    if commands::dispatch(&args, &envelope, &command).await {
+                                        continue;
+                                    }
+                                }
+                                args.events.publish(BotEvent::Chat(envelope));
                             }
+                        "USERNOTICE" => {
+                            args.events.publish(BotEvent::Alert(ChatEnvelope::new(irc_message.clone())));
+                        }
+                        "ROOMSTATE" => {
+                            args.chat_state.update_room_state(&irc_message).await;
+                        }
+                        "GLOBALUSERSTATE" => {
+                            args.bot_info.set_user_id(irc_message.tag("user-id").unwrap_or_default()).await;
+                        }
+                        "USERSTATE" => {
+                            args.chat_state.set_bot_permission(Permission::from_message(&irc_message)).await;
+                        }
                         "PING" => {
                             write.send("PONG :tmi.twitch.tv".to_ws_text()).await?;
                         }
@@ -122,10 +169,29 @@ pub async fn start(args: Arc<Args>) -> Result<()> {
       // }
   }
 
-        ret_val = args.twitch_queue.recv() => {
-            let payload = ret_val;
-            println!("{}{} Sending: {}", "[TX]".green(), "[MSG]".blue(), payload);
-            write.send(format!("PRIVMSG #{} :{}", user_channel, payload).to_ws_text()).await?;
+        event = outgoing.recv() => {
+            let (channel, payload, reply_to) = match &*event {
+                BotEvent::SendChat { channel, text } => (channel.as_str(), text.clone(), None),
+                // Answers go back to the channel of the question
+                BotEvent::LlmReply { text, reply_to, .. } => (
+                    reply_to.as_ref().map(|envelope| envelope.channel.as_str()).unwrap_or(&user_channel),
+                    text.clone(),
+                    reply_to.as_ref(),
+                ),
+                _ => continue,
+            };
+            // Last line of defense, a line break would send a second IRC command
+            let payload = payload.replace(['\r', '\n'], " ");
+            let thread = reply_to
+                .filter(|_| reply_in_thread)
+                .and_then(|envelope| envelope.message_id())
+                .map(|message_id| format!("@reply-parent-msg-id={} ", message_id))
+                .unwrap_or_default();
+            match reply_to {
+                Some(envelope) => println!("{}{} Sending to {}: {}", "[TX]".green(), "[MSG]".blue(), envelope.trace_id(), payload),
+                None => println!("{}{} Sending: {}", "[TX]".green(), "[MSG]".blue(), payload),
+            }
+            write.send(format!("{}PRIVMSG #{} :{}", thread, channel, payload).to_ws_text()).await?;
         }
   
   }
@@ -0,0 +1,66 @@
+// Voices assigned to each chatter, kept on disk so a chatter always sounds the same
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+use serde::{ Deserialize, Serialize };
+
+use crate::config_manager::ConfigManager;
+
+pub const VOICE_ASSIGNMENTS_FILE: &str = "tts_voice_assignments.toml";
+
+/// FNV-1a, stable across runs and rust versions unlike the std hasher
+pub fn stable_hash(value: &str) -> u64 {
+    value.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ (byte as u64)).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct UserVoices {
+    /// Voice picked with !myvoice, used whatever the language
+    pub chosen: Option<String>,
+    /// Voice assigned for each locale the chatter wrote in
+    pub by_locale: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct VoiceAssignments {
+    /// By user-id, or nickname when the user-id is unknown
+    pub users: HashMap<String, UserVoices>,
+}
+
+impl ConfigManager for VoiceAssignments {}
+
+impl VoiceAssignments {
+    pub async fn load() -> Result<Self> {
+        VoiceAssignments::load_config::<VoiceAssignments>(
+            VoiceAssignments::default(),
+            VOICE_ASSIGNMENTS_FILE
+        ).await
+    }
+
+    pub async fn save(&self) -> Result<()> {
+        VoiceAssignments::save_config(self, VOICE_ASSIGNMENTS_FILE).await
+    }
+
+    pub fn chosen(&self, user: &str) -> Option<&str> {
+        self.users.get(user)?.chosen.as_deref()
+    }
+
+    pub fn for_locale(&self, user: &str, locale: &str) -> Option<&str> {
+        self.users.get(user)?.by_locale.get(locale).map(|voice| voice.as_str())
+    }
+
+    pub fn set_chosen(&mut self, user: &str, voice: Option<String>) {
+        self.users.entry(user.to_string()).or_default().chosen = voice;
+    }
+
+    pub fn set_for_locale(&mut self, user: &str, locale: &str, voice: &str) {
+        self.users
+            .entry(user.to_string())
+            .or_default()
+            .by_locale.insert(locale.to_string(), voice.to_string());
+    }
+}
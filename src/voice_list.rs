@@ -0,0 +1,104 @@
+// Voice list of the TTS engine, cached on disk so the TTS starts even when the engine is unreachable
+#![allow(dead_code)]
+
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
+
+use anyhow::Result;
+use serde::{ Deserialize, Serialize };
+
+use crate::colors::Colorize;
+use crate::tts_engine::{ TtsBackend, TtsEngine, VoiceInfo };
+
+/// Longest wait for the engine to answer with its voices
+const FETCH_TIMEOUT: Duration = Duration::from_secs(15);
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct CachedVoiceList {
+    /// Unix time of the download
+    fetched_at: u64,
+    voices: Vec<VoiceInfo>,
+}
+
+#[derive(Debug)]
+pub struct VoiceListCache {
+    path: String,
+    refresh_interval: Duration,
+    retry_interval: Duration,
+    list: Option<CachedVoiceList>,
+    last_attempt: Option<Instant>,
+}
+
+impl VoiceListCache {
+    /// Loads the list cached for `engine_name`, if any
+    pub fn load(engine_name: &str, refresh_interval: Duration, retry_interval: Duration) -> Self {
+        let path = format!("tts_voices_{}.json", engine_name);
+        let list = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|content| serde_json::from_str::<CachedVoiceList>(&content).ok());
+        if let Some(list) = &list {
+            println!("[TTS] {} voices cached in {}", list.voices.len(), path);
+        }
+        VoiceListCache { path, refresh_interval, retry_interval, list, last_attempt: None }
+    }
+
+    pub fn voices(&self) -> Vec<VoiceInfo> {
+        self.list
+            .as_ref()
+            .map(|list| list.voices.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn is_stale(&self) -> bool {
+        match &self.list {
+            Some(list) =>
+                list.voices.is_empty() ||
+                    now_secs().saturating_sub(list.fetched_at) >= self.refresh_interval.as_secs(),
+            None => true,
+        }
+    }
+
+    fn save(&self) -> Result<()> {
+        if let Some(list) = &self.list {
+            std::fs::write(&self.path, serde_json::to_string(list)?)?;
+        }
+        Ok(())
+    }
+
+    /// Downloads the voices again if the list is stale, at most once per retry interval.
+    /// Returns the new list, None if nothing changed.
+    pub async fn refresh(&mut self, engine: &mut TtsBackend) -> Option<Vec<VoiceInfo>> {
+        if !self.is_stale() {
+            return None;
+        }
+        if self.last_attempt.is_some_and(|attempt| attempt.elapsed() < self.retry_interval) {
+            return None;
+        }
+        self.last_attempt = Some(Instant::now());
+
+        let voices = match tokio::time::timeout(FETCH_TIMEOUT, engine.list_voices()).await {
+            Ok(Ok(voices)) if !voices.is_empty() => voices,
+            Ok(Ok(_)) => {
+                println!("{} The {} engine has no voices", "[TTS]".red(), engine.name());
+                return None;
+            }
+            Ok(Err(err)) => {
+                println!("{} Failed to get the voices of the {} engine: {}", "[TTS]".red(), engine.name(), err);
+                return None;
+            }
+            Err(_) => {
+                println!("{} The {} engine did not list its voices in time", "[TTS]".red(), engine.name());
+                return None;
+            }
+        };
+        println!("[TTS] {} voices available from the {} engine", voices.len(), engine.name());
+        self.list = Some(CachedVoiceList { fetched_at: now_secs(), voices: voices.clone() });
+        if let Err(err) = self.save() {
+            println!("{} Failed to save {}: {}", "[TTS]".red(), self.path, err);
+        }
+        Some(voices)
+    }
+}
@@ -0,0 +1,189 @@
+// Composable search of the TTS voices, explaining what is missing when nothing matches
+#![allow(dead_code)]
+
+use std::fmt;
+
+use serde::{ Deserialize, Serialize };
+
+use crate::tts_engine::VoiceInfo;
+
+/// Alternatives listed when no voice matches
+const MAX_ALTERNATIVES: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VoiceKind {
+    Neural,
+    Standard,
+}
+
+/// Every set criterion must match, unset ones match any voice
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VoiceQuery {
+    /// Locale prefix, "it" matches it-IT and it-CH, "it-IT" only it-IT
+    pub language: Option<String>,
+    /// Male or Female
+    pub gender: Option<String>,
+    /// Part of the name, short name or friendly name, e.g. "diego"
+    pub name: Option<String>,
+    /// Content categories or personalities the voice must all have, e.g. Friendly
+    pub tags: Vec<String>,
+    pub kind: Option<VoiceKind>,
+}
+
+fn contains_ignore_case(text: &str, part: &str) -> bool {
+    text.to_lowercase().contains(&part.to_lowercase())
+}
+
+impl VoiceQuery {
+    pub fn new() -> Self {
+        VoiceQuery::default()
+    }
+
+    pub fn language(mut self, language: &str) -> Self {
+        self.language = Some(language.to_string());
+        self
+    }
+
+    pub fn gender(mut self, gender: &str) -> Self {
+        self.gender = Some(gender.to_string());
+        self
+    }
+
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    pub fn tag(mut self, tag: &str) -> Self {
+        self.tags.push(tag.to_string());
+        self
+    }
+
+    pub fn kind(mut self, kind: VoiceKind) -> Self {
+        self.kind = Some(kind);
+        self
+    }
+
+    /// `self` with the criteria set in `other` replacing its own
+    pub fn merge(&self, other: &VoiceQuery) -> VoiceQuery {
+        VoiceQuery {
+            language: other.language.clone().or(self.language.clone()),
+            gender: other.gender.clone().or(self.gender.clone()),
+            name: other.name.clone().or(self.name.clone()),
+            tags: if other.tags.is_empty() { self.tags.clone() } else { other.tags.clone() },
+            kind: other.kind.or(self.kind),
+        }
+    }
+
+    /// Criteria `voice` does not meet, empty if it matches
+    pub fn mismatches(&self, voice: &VoiceInfo) -> Vec<&'static str> {
+        let mut mismatches = Vec::new();
+        if let Some(language) = &self.language {
+            let locale = voice.locale.as_deref().unwrap_or_default().to_lowercase();
+            let language = language.to_lowercase();
+            if locale != language && !locale.starts_with(&format!("{}-", language)) {
+                mismatches.push("language");
+            }
+        }
+        if let Some(gender) = &self.gender {
+            if !voice.gender.as_deref().is_some_and(|voice_gender| voice_gender.eq_ignore_ascii_case(gender)) {
+                mismatches.push("gender");
+            }
+        }
+        if let Some(name) = &self.name {
+            let names = [Some(voice.name.as_str()), voice.short_name.as_deref(), voice.friendly_name.as_deref()];
+            if !names.into_iter().flatten().any(|voice_name| contains_ignore_case(voice_name, name)) {
+                mismatches.push("name");
+            }
+        }
+        let has_tags = self.tags
+            .iter()
+            .all(|tag| voice.tags.iter().any(|voice_tag| voice_tag.eq_ignore_ascii_case(tag)));
+        if !has_tags {
+            mismatches.push("tags");
+        }
+        if let Some(kind) = self.kind {
+            let voice_kind = if voice.is_neural() { VoiceKind::Neural } else { VoiceKind::Standard };
+            if voice_kind != kind {
+                mismatches.push("kind");
+            }
+        }
+        mismatches
+    }
+
+    pub fn matches(&self, voice: &VoiceInfo) -> bool {
+        self.mismatches(voice).is_empty()
+    }
+
+    /// Why nothing in `voices` matches, with the voices missing the fewest criteria
+    pub fn explain(&self, voices: &[VoiceInfo]) -> NoVoiceMatch {
+        let mut alternatives = voices
+            .iter()
+            .map(|voice| (voice, self.mismatches(voice)))
+            .collect::<Vec<_>>();
+        alternatives.sort_by_key(|(_, mismatches)| mismatches.len());
+        NoVoiceMatch {
+            query: self.to_string(),
+            alternatives: alternatives
+                .into_iter()
+                .take(MAX_ALTERNATIVES)
+                .map(|(voice, mismatches)| {
+                    let name = voice.short_name.clone().unwrap_or(voice.name.clone());
+                    (name, mismatches.into_iter().map(|mismatch| mismatch.to_string()).collect())
+                })
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Display for VoiceQuery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut criteria = Vec::new();
+        if let Some(language) = &self.language {
+            criteria.push(format!("language {}", language));
+        }
+        if let Some(gender) = &self.gender {
+            criteria.push(format!("gender {}", gender));
+        }
+        if let Some(name) = &self.name {
+            criteria.push(format!("name \"{}\"", name));
+        }
+        if !self.tags.is_empty() {
+            criteria.push(format!("tags {}", self.tags.join("+")));
+        }
+        if let Some(kind) = self.kind {
+            criteria.push(format!("{:?}", kind).to_lowercase());
+        }
+        match criteria.is_empty() {
+            true => write!(f, "any voice"),
+            false => write!(f, "{}", criteria.join(", ")),
+        }
+    }
+}
+
+/// No voice matches a query
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NoVoiceMatch {
+    pub query: String,
+    /// Nearest voices and the criteria each one misses
+    pub alternatives: Vec<(String, Vec<String>)>,
+}
+
+impl fmt::Display for NoVoiceMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no voice with {}", self.query)?;
+        if self.alternatives.is_empty() {
+            return write!(f, ", no voices available");
+        }
+        let alternatives = self.alternatives
+            .iter()
+            .map(|(name, mismatches)| format!("{} (other {})", name, mismatches.join(", ")))
+            .collect::<Vec<String>>()
+            .join("; ");
+        write!(f, ", nearest: {}", alternatives)
+    }
+}
+
+impl std::error::Error for NoVoiceMatch {}